@@ -0,0 +1,154 @@
+use crate::error::AppError;
+use crate::logging;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::sync::Arc;
+use tracing::{info, warn, Span};
+use warp::filters::BoxedFilter;
+use warp::Filter;
+
+/// What a key may do; `Admin` includes `Read`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Recommendations, clients, products and status
+    Read,
+    /// Training and model versions
+    Admin,
+}
+
+/// A key of the keys file, given either in clear or as the hex SHA-256 of the key
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    name: String,
+    key: Option<String>,
+    sha256: Option<String>,
+    scope: Scope,
+}
+
+#[derive(Deserialize)]
+struct KeysFile {
+    #[serde(default, rename = "key")]
+    keys: Vec<KeyEntry>,
+}
+
+struct ApiKey {
+    name: String,
+    digest: [u8; 32],
+    scope: Scope,
+}
+
+/// The API keys allowed to call the service
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    /// Reads the `[[key]]` entries of a TOML file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let file: KeysFile =
+            toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path, e))?;
+        if file.keys.is_empty() {
+            return Err(format!("{} has no keys", path));
+        }
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|entry| {
+                let digest = match (&entry.key, &entry.sha256) {
+                    (Some(key), None) => digest(key),
+                    (None, Some(hash)) => parse_digest(hash)
+                        .ok_or_else(|| format!("key '{}': invalid sha256", entry.name))?,
+                    _ => return Err(format!("key '{}' needs either key or sha256", entry.name)),
+                };
+                Ok(ApiKey {
+                    name: entry.name,
+                    digest,
+                    scope: entry.scope,
+                })
+            })
+            .collect::<Result<_, String>>()
+            .map_err(|e| format!("Invalid {}: {}", path, e))?;
+        Ok(ApiKeys { keys })
+    }
+
+    fn find(&self, token: &str) -> Option<&ApiKey> {
+        let digest = digest(token);
+        self.keys.iter().find(|key| key.digest == digest)
+    }
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn parse_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Passes requests carrying a key with `scope` in `Authorization: Bearer` or
+/// `X-Api-Key`, and records the key's name in the request span. Everything passes
+/// when `keys` is `None`.
+pub fn require(keys: Option<Arc<ApiKeys>>, scope: Scope) -> BoxedFilter<()> {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and_then(
+            move |authorization: Option<String>, api_key: Option<String>| {
+                let keys = keys.clone();
+                async move {
+                    let Some(keys) = keys else {
+                        return Ok(());
+                    };
+                    let token = api_key.or_else(|| {
+                        authorization
+                            .and_then(|header| header.strip_prefix("Bearer ").map(str::to_string))
+                    });
+                    let Some(token) = token else {
+                        return Err(warp::reject::custom(AppError::Unauthorized(
+                            "missing API key".to_string(),
+                        )));
+                    };
+                    match keys.find(token.trim()) {
+                        None => {
+                            warn!("Request with an unknown API key");
+                            Err(warp::reject::custom(AppError::Unauthorized(
+                                "invalid API key".to_string(),
+                            )))
+                        }
+                        Some(key) if key.scope < scope => Err(warp::reject::custom(
+                            AppError::Forbidden(format!("key '{}' can't do this", key.name)),
+                        )),
+                        Some(key) => {
+                            Span::current().record("key", key.name.as_str());
+                            Ok(())
+                        }
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .boxed()
+}
+
+/// Audit trail of client data access; the key is a field of the request span. The
+/// client is identified even when ids are redacted elsewhere, so the `audit` target
+/// must go to a sink with restricted access.
+pub fn audit(action: &str, client_id: &str) {
+    info!(
+        target: "audit",
+        action,
+        client = %logging::audited_client(client_id),
+        "Client data accessed"
+    );
+}
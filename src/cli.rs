@@ -0,0 +1,413 @@
+use crate::config::Overrides;
+use crate::models::db::Database;
+use crate::services::events::TrainingEvents;
+use crate::services::matrixcache::{load_matrix, read_matrix_file};
+use crate::services::modelfile::{convert_model, load_model};
+use crate::services::modelserver::build_model;
+use crate::services::promotion::evaluate;
+use crate::services::registry::ModelVersion;
+use crate::services::tenants::{Tenant, TenantsConfig};
+use crate::services::training::{
+    find_best_als_model, HyperparameterGrid, TrainingRun, TrainingState,
+};
+use clap::{Parser, Subcommand};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::signal;
+use tracing::info;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Parser)]
+#[command(version, about = "Product recommendations for Aspel SAE companies")]
+pub struct Cli {
+    /// Tenant to work on; the default tenant when omitted
+    #[arg(long, global = true)]
+    pub tenant: Option<String>,
+    /// Config file; `CONFIG_FILE` or ./config.toml when omitted
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+    /// Address the server listens on
+    #[arg(long, global = true)]
+    pub host: Option<std::net::IpAddr>,
+    /// Port the server listens on
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serve the API and run the scheduled trainings (the default)
+    Serve,
+    /// Train and publish a model once, without starting the server
+    Train {
+        /// Retrain the served model's hyperparameters starting from its factors
+        #[arg(long)]
+        refit: bool,
+        /// JSON file with the hyperparameter grid to search
+        #[arg(long, conflicts_with = "refit")]
+        grid: Option<String>,
+    },
+    /// Compute the EPR of a model on a matrix snapshot
+    Evaluate {
+        /// Model file; the tenant's served model when omitted
+        #[arg(long)]
+        model: Option<String>,
+        /// Matrix cache or plain matrix JSON; the tenant's matrix cache when omitted
+        #[arg(long)]
+        matrix: Option<String>,
+    },
+    /// Print the products recommended to a client
+    Recommend {
+        client: String,
+        #[arg(short, long, default_value_t = 10)]
+        n: usize,
+        #[arg(long)]
+        model: Option<String>,
+    },
+    /// Write a model's factors to CSV, or the whole model to JSON or binary by the
+    /// output extension
+    Export {
+        output: String,
+        #[arg(long)]
+        model: Option<String>,
+    },
+    /// Print a model's metadata and factor statistics
+    InspectModel {
+        #[arg(long)]
+        model: Option<String>,
+        /// Also print the factors of this client
+        #[arg(long)]
+        client: Option<String>,
+        /// Also print the factors of this product
+        #[arg(long)]
+        product: Option<String>,
+    },
+    /// Convert a model file between the JSON and binary formats
+    ConvertModel { input: String, output: String },
+    /// Manage the model registry
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ModelsCommand {
+    /// List the kept model versions
+    List,
+    /// Serve a kept version again
+    Activate { version: u64 },
+    /// Serve the version before the active one
+    Rollback,
+}
+
+impl Cli {
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            config_file: self.config.clone(),
+            host: self.host,
+            port: self.port,
+        }
+    }
+}
+
+/// Runs every command but `serve`
+pub async fn run(
+    command: Command,
+    tenant: Option<&str>,
+    tenants_config: &TenantsConfig,
+) -> Result<(), Error> {
+    let tenant = find_tenant(tenants_config, tenant)?;
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Train { refit, grid } => train(tenant, refit, grid).await,
+        Command::Evaluate { model, matrix } => {
+            let model = model.as_deref().unwrap_or(&tenant.model_file);
+            let matrix = matrix.as_deref().unwrap_or(&tenant.matrix_file);
+            evaluate_model(model, matrix)
+        }
+        Command::Recommend { client, n, model } => {
+            let (json_data, _) = load_model(model.as_deref().unwrap_or(&tenant.model_file))?;
+            let products = build_model(&json_data, 0).recommend(&client, Some(n));
+            if products.is_empty() {
+                return Err(format!("Unknown client: {}", client).into());
+            }
+            for (rank, product) in products.iter().enumerate() {
+                println!("{:>3}  {}", rank + 1, product);
+            }
+            Ok(())
+        }
+        Command::Export { output, model } => {
+            export(model.as_deref().unwrap_or(&tenant.model_file), &output)
+        }
+        Command::InspectModel {
+            model,
+            client,
+            product,
+        } => inspect_model(
+            model.as_deref().unwrap_or(&tenant.model_file),
+            client.as_deref(),
+            product.as_deref(),
+        ),
+        Command::ConvertModel { input, output } => {
+            let metadata = convert_model(&input, &output)?;
+            println!(
+                "Converted {} to {} ({} clients, {} products)",
+                input, output, metadata.num_clients, metadata.num_products
+            );
+            Ok(())
+        }
+        Command::Models { command } => manage_models(command, tenant),
+    }
+}
+
+fn find_tenant<'a>(
+    tenants_config: &'a TenantsConfig,
+    name: Option<&str>,
+) -> Result<&'a Tenant, Error> {
+    let name = name.unwrap_or(&tenants_config.default);
+    tenants_config
+        .tenants
+        .iter()
+        .find(|tenant| tenant.name == name)
+        .ok_or_else(|| format!("Unknown tenant: {}", name).into())
+}
+
+/// Extracts the matrix and trains until the run finishes or Ctrl+C cancels it
+async fn train(tenant: &Tenant, refit: bool, grid_file: Option<String>) -> Result<(), Error> {
+    let (grid, warm_start) = if refit {
+        let (json_data, _) =
+            load_model(&tenant.model_file).map_err(|e| format!("No model to refit: {}", e))?;
+        (
+            HyperparameterGrid::single(
+                &json_data.hyperparameters,
+                tenant.training.refit_iterations,
+            ),
+            Some(Arc::new(json_data)),
+        )
+    } else {
+        match grid_file {
+            Some(path) => (serde_json::from_slice(&fs::read(path)?)?, None),
+            None => (HyperparameterGrid::default(), None),
+        }
+    };
+
+    grid.validate()
+        .map_err(|e| format!("Invalid hyperparameter grid: {}", e))?;
+
+    let db = Database::connect(&tenant.database)
+        .await
+        .map_err(|e| e.to_string())?;
+    let matrix = load_matrix(
+        &db,
+        &tenant.matrix_file,
+        tenant.training.matrix_rebuild_days,
+    )
+    .await?;
+
+    let run = TrainingRun::new(TrainingEvents::default());
+    let mut training = {
+        let run = run.clone();
+        let registry = tenant.registry();
+        let gate = tenant.training.gate.clone();
+        tokio::task::spawn_blocking(move || {
+            find_best_als_model(matrix, &grid, warm_start, &run, &registry, &gate);
+        })
+    };
+    tokio::select! {
+        result = &mut training => result?,
+        _ = signal::ctrl_c() => {
+            info!("Received Ctrl+C, cancelling training");
+            run.cancel();
+            training.await?;
+        }
+    }
+
+    let status = run.status();
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    match status.state {
+        TrainingState::Published => Ok(()),
+        _ => Err(status
+            .message
+            .unwrap_or_else(|| "Training did not finish".to_string())
+            .into()),
+    }
+}
+
+fn evaluate_model(model_file: &str, matrix_file: &str) -> Result<(), Error> {
+    let (json_data, metadata) = load_model(model_file)?;
+    let matrix = read_matrix_file(matrix_file).map_err(|e| e.to_string())?;
+
+    let known_clients = matrix
+        .keys()
+        .filter(|client| json_data.client_index.contains_key(*client))
+        .count();
+    let products = matrix
+        .values()
+        .flat_map(|products| products.keys())
+        .collect::<HashSet<_>>();
+    let known_products = products
+        .iter()
+        .filter(|product| json_data.product_index.contains_key(**product))
+        .count();
+    println!(
+        "Clients known by the model: {}/{}",
+        known_clients,
+        matrix.len()
+    );
+    println!(
+        "Products known by the model: {}/{}",
+        known_products,
+        products.len()
+    );
+    if let Some(epr) = metadata.epr {
+        println!("EPR when trained: {:.2}%", epr * 100.0);
+    }
+    match evaluate(&json_data, &matrix) {
+        Some(epr) => println!("EPR on {}: {:.2}%", matrix_file, epr * 100.0),
+        None => println!("No interaction in {} is known by the model", matrix_file),
+    }
+    Ok(())
+}
+
+fn export(model_file: &str, output: &str) -> Result<(), Error> {
+    let is_csv = Path::new(output)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    if !is_csv {
+        convert_model(model_file, output)?;
+        println!("Exported {} to {}", model_file, output);
+        return Ok(());
+    }
+
+    let (json_data, _) = load_model(model_file)?;
+    let mut writer = csv::Writer::from_writer(File::create(output)?);
+    let num_factors = json_data.hyperparameters.num_factors;
+    let mut header = vec!["kind".to_string(), "id".to_string()];
+    header.extend((0..num_factors).map(|i| format!("f{}", i)));
+    writer.write_record(&header)?;
+    for (kind, index, factors) in [
+        ("client", &json_data.client_index, &json_data.client_factors),
+        (
+            "product",
+            &json_data.product_index,
+            &json_data.product_factors,
+        ),
+    ] {
+        let mut ids = index.iter().collect::<Vec<_>>();
+        ids.sort_by_key(|(_, &i)| i);
+        for (id, &i) in ids {
+            let mut record = vec![kind.to_string(), id.clone()];
+            record.extend(factors[i].iter().map(|factor| factor.to_string()));
+            writer.write_record(&record)?;
+        }
+    }
+    writer.flush()?;
+    println!("Exported the factors of {} to {}", model_file, output);
+    Ok(())
+}
+
+fn inspect_model(
+    model_file: &str,
+    client: Option<&str>,
+    product: Option<&str>,
+) -> Result<(), Error> {
+    let (json_data, metadata) = load_model(model_file)?;
+    println!(
+        "File: {} ({} bytes)",
+        model_file,
+        fs::metadata(model_file)?.len()
+    );
+    println!("{}", serde_json::to_string_pretty(&metadata)?);
+
+    for (kind, factors) in [
+        ("Client", &json_data.client_factors),
+        ("Product", &json_data.product_factors),
+    ] {
+        let norms = factors
+            .iter()
+            .map(|row| row.iter().map(|f| f * f).sum::<f64>().sqrt())
+            .collect::<Vec<_>>();
+        if norms.is_empty() {
+            continue;
+        }
+        println!(
+            "{} factor norms: min {:.4}, mean {:.4}, max {:.4}",
+            kind,
+            norms.iter().cloned().fold(f64::INFINITY, f64::min),
+            norms.iter().sum::<f64>() / norms.len() as f64,
+            norms.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        );
+    }
+
+    let print_factors = |kind: &str,
+                         id: &str,
+                         index: &HashMap<String, usize>,
+                         factors: &[Vec<f64>]|
+     -> Result<(), Error> {
+        let i = index
+            .get(id)
+            .ok_or_else(|| format!("Unknown {}: {}", kind, id))?;
+        println!("{} {}: {:?}", kind, id, factors[*i]);
+        Ok(())
+    };
+    if let Some(client) = client {
+        print_factors(
+            "client",
+            client,
+            &json_data.client_index,
+            &json_data.client_factors,
+        )?;
+    }
+    if let Some(product) = product {
+        print_factors(
+            "product",
+            product,
+            &json_data.product_index,
+            &json_data.product_factors,
+        )?;
+    }
+    Ok(())
+}
+
+fn manage_models(command: ModelsCommand, tenant: &Tenant) -> Result<(), Error> {
+    let registry = tenant.registry();
+
+    let print_version = |entry: &ModelVersion, active: bool| {
+        let metadata = &entry.metadata;
+        println!(
+            "{} {:>4}  {}  EPR {}  {} clients, {} products, {} interactions  {:?}",
+            if active { "*" } else { " " },
+            entry.version,
+            metadata
+                .trained_at
+                .map(|trained_at| trained_at.to_rfc3339())
+                .unwrap_or_else(|| "-".to_string()),
+            metadata
+                .epr
+                .map(|epr| format!("{:.2}%", epr * 100.0))
+                .unwrap_or_else(|| "-".to_string()),
+            metadata.num_clients,
+            metadata.num_products,
+            metadata.num_interactions,
+            metadata.hyperparameters,
+        );
+    };
+
+    match command {
+        ModelsCommand::List => {
+            let versions = registry.list()?;
+            for entry in &versions.versions {
+                print_version(entry, versions.active == Some(entry.version));
+            }
+        }
+        ModelsCommand::Activate { version } => print_version(&registry.activate(version)?, true),
+        ModelsCommand::Rollback => print_version(&registry.rollback()?, true),
+    }
+    Ok(())
+}
@@ -0,0 +1,448 @@
+use crate::auth::ApiKeys;
+use crate::models::schema::SchemaOverrides;
+use crate::services::modelfile::Precision;
+use crate::services::promotion::PromotionGate;
+use crate::services::schedule::parse_timezone;
+use crate::services::tenants::{build_tenants, TenantEntry, TenantsConfig, TenantsFile};
+use chrono_tz::Tz;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+type Error = Box<dyn std::error::Error>;
+
+const DEFAULT_CONFIG_FILE: &str = "./config.toml";
+const DEFAULT_PORT: u16 = 3030;
+const DEFAULT_POOL_SIZE: usize = 4;
+const DEFAULT_SCHEDULE: &str = "0 0 0 * * *";
+const DEFAULT_TIMEZONE: &str = "Mexico/General";
+
+/// Settings of the whole process, read once at startup from the config file, the
+/// environment and the command line, each overriding the previous one
+pub struct Config {
+    pub server: ServerConfig,
+    pub logging: LoggingConfig,
+    /// API keys, or `None` when the API is open
+    pub auth: Option<Arc<ApiKeys>>,
+    pub catalog: CatalogConfig,
+    pub training: TrainingConfig,
+    pub tenants: TenantsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+/// How client ids appear in the logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIdLogging {
+    #[default]
+    Plain,
+    /// Salted hash, so the lines of a client can still be correlated
+    Hash,
+    /// Left out, except in the `audit` target, which logs the salted hash
+    Redact,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// `tracing` filter, e.g. `info` or `predictive_module=debug`; `RUST_LOG` wins
+    pub level: String,
+    pub client_ids: ClientIdLogging,
+    pub client_id_salt: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Text,
+            level: "info".to_string(),
+            client_ids: ClientIdLogging::Plain,
+            client_id_salt: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// TOML file with the `[[key]]`s allowed to call the API; the API is open without it
+    pub keys_file: Option<String>,
+}
+
+/// In-memory copy of the clients and products the API searches
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CatalogConfig {
+    pub enabled: bool,
+    /// Minutes between reloads besides the one after each new model; 0 disables them
+    pub refresh_minutes: u64,
+}
+
+impl Default for CatalogConfig {
+    fn default() -> Self {
+        CatalogConfig {
+            enabled: true,
+            refresh_minutes: 60,
+        }
+    }
+}
+
+/// Database every tenant reads from unless it overrides part of it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    #[serde(rename = "type")]
+    pub db_type: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub name: Option<String>,
+    pub files_dir: Option<String>,
+    pub pool_size: usize,
+    pub schema_file: Option<String>,
+    #[serde(flatten)]
+    pub schema: SchemaOverrides,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            db_type: None,
+            host: None,
+            port: None,
+            username: None,
+            password: None,
+            name: None,
+            files_dir: None,
+            pool_size: DEFAULT_POOL_SIZE,
+            schema_file: None,
+            schema: SchemaOverrides::default(),
+        }
+    }
+}
+
+/// How models are trained, stored and published
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TrainingConfig {
+    /// Default cron expression of the tenants without a schedule
+    pub schedule: String,
+    /// Default timezone of the schedules
+    pub timezone: String,
+    /// Iterations of a warm-started refit
+    pub refit_iterations: usize,
+    /// Age after which the matrix cache is extracted again from scratch
+    pub matrix_rebuild_days: i64,
+    /// Models kept in each registry
+    pub registry_keep: usize,
+    /// Age after which the served model makes `/readyz` fail, 0 to never expire
+    pub max_model_age_hours: u64,
+    pub precision: Precision,
+    pub gate: PromotionGate,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        TrainingConfig {
+            schedule: DEFAULT_SCHEDULE.to_string(),
+            timezone: DEFAULT_TIMEZONE.to_string(),
+            refit_iterations: 10,
+            matrix_rebuild_days: 7,
+            registry_keep: 5,
+            max_model_age_hours: 48,
+            precision: Precision::F32,
+            gate: PromotionGate::default(),
+        }
+    }
+}
+
+impl TrainingConfig {
+    pub fn timezone(&self) -> Tz {
+        parse_timezone(&self.timezone).unwrap_or(chrono_tz::Mexico::General)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        parse_timezone(&self.timezone)?;
+        if self.refit_iterations == 0 {
+            return Err("refit_iterations must be at least 1".to_string());
+        }
+        if self.matrix_rebuild_days < 0 {
+            return Err("matrix_rebuild_days can't be negative".to_string());
+        }
+        if self.registry_keep == 0 {
+            return Err("registry_keep must be at least 1".to_string());
+        }
+        self.gate.validate()
+    }
+}
+
+/// The config file; every section is optional
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerConfig,
+    logging: LoggingConfig,
+    auth: AuthConfig,
+    catalog: CatalogConfig,
+    database: DatabaseConfig,
+    training: TrainingConfig,
+    /// Tenant answering the routes without a `/t/{tenant}` prefix
+    default: Option<String>,
+    #[serde(rename = "tenant")]
+    tenants: Vec<TenantEntry>,
+    /// Separate file listing the tenants, replacing the ones above
+    tenants_file: Option<String>,
+}
+
+/// Settings given on the command line, which take precedence over everything else
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub config_file: Option<String>,
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+impl Config {
+    /// Reads the config file (`--config`, `CONFIG_FILE` or `./config.toml` if it
+    /// exists), applies the environment and `overrides` on top and validates the
+    /// result, so a bad setting stops the process at startup
+    pub fn load(overrides: &Overrides) -> Result<Config, Error> {
+        let path = overrides
+            .config_file
+            .clone()
+            .or_else(|| env::var("CONFIG_FILE").ok())
+            .or_else(|| {
+                Path::new(DEFAULT_CONFIG_FILE)
+                    .exists()
+                    .then(|| DEFAULT_CONFIG_FILE.to_string())
+            });
+        let mut file = match &path {
+            Some(path) => read_toml::<ConfigFile>(path)?,
+            None => ConfigFile::default(),
+        };
+        apply_env(&mut file).map_err(|e| format!("Invalid environment: {}", e))?;
+        if let Some(host) = overrides.host {
+            file.server.host = host;
+        }
+        if let Some(port) = overrides.port {
+            file.server.port = port;
+        }
+
+        file.training
+            .validate()
+            .map_err(|e| format!("Invalid training settings: {}", e))?;
+
+        let auth = match &file.auth.keys_file {
+            Some(path) => Some(Arc::new(ApiKeys::load(path)?)),
+            None => None,
+        };
+
+        let (default, tenants, source) = match file.tenants_file {
+            Some(tenants_file) => {
+                let tenants = read_toml::<TenantsFile>(&tenants_file)?;
+                (tenants.default, tenants.tenants, tenants_file)
+            }
+            None => (
+                file.default,
+                file.tenants,
+                path.unwrap_or_else(|| "the configuration".to_string()),
+            ),
+        };
+        let tenants = build_tenants(default, tenants, &file.database, &file.training, &source)?;
+
+        Ok(Config {
+            server: file.server,
+            logging: file.logging,
+            auth,
+            catalog: file.catalog,
+            training: file.training,
+            tenants,
+        })
+    }
+}
+
+fn read_toml<T: DeserializeOwned>(path: &str) -> Result<T, Error> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Ok(toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path, e))?)
+}
+
+/// Applies the environment variables on top of the config file
+fn apply_env(file: &mut ConfigFile) -> Result<(), String> {
+    let server = &mut file.server;
+    set(&mut server.host, "SERVER_HOST")?;
+    set(&mut server.port, "SERVER_PORT")?;
+
+    let logging = &mut file.logging;
+    if let Ok(format) = env::var("LOG_FORMAT") {
+        logging.format = match format.as_str() {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => return Err(format!("LOG_FORMAT must be text or json, not '{}'", format)),
+        };
+    }
+    set(&mut logging.level, "LOG_LEVEL")?;
+    if let Ok(client_ids) = env::var("LOG_CLIENT_IDS") {
+        logging.client_ids = match client_ids.as_str() {
+            "plain" => ClientIdLogging::Plain,
+            "hash" => ClientIdLogging::Hash,
+            "redact" => ClientIdLogging::Redact,
+            _ => {
+                return Err(format!(
+                    "LOG_CLIENT_IDS must be plain, hash or redact, not '{}'",
+                    client_ids
+                ))
+            }
+        };
+    }
+    set(&mut logging.client_id_salt, "LOG_CLIENT_ID_SALT")?;
+    set_option(&mut file.auth.keys_file, "API_KEYS_FILE")?;
+    if let Some(enabled) = flag("CATALOG_CACHE")? {
+        file.catalog.enabled = enabled;
+    }
+    set(&mut file.catalog.refresh_minutes, "CATALOG_REFRESH_MINUTES")?;
+
+    let database = &mut file.database;
+    set_option(&mut database.db_type, "DB_TYPE")?;
+    set_option(&mut database.host, "DB_HOST")?;
+    set_option(&mut database.port, "DB_PORT")?;
+    set_option(&mut database.username, "DB_USERNAME")?;
+    set_option(&mut database.password, "DB_PASSWORD")?;
+    set_option(&mut database.name, "DB_NAME")?;
+    set_option(&mut database.files_dir, "FILES_DIR")?;
+    set(&mut database.pool_size, "DB_POOL_SIZE")?;
+    set_option(&mut database.schema_file, "SCHEMA_FILE")?;
+    let tables = &mut database.schema.tables;
+    set_option(&mut tables.fact, "TABLE_FACT")?;
+    set_option(&mut tables.par_fact, "TABLE_PAR_FACT")?;
+    set_option(&mut tables.client, "TABLE_CLIENT")?;
+    set_option(&mut tables.inve, "TABLE_INVE")?;
+    set_option(&mut tables.dev, "TABLE_DEV")?;
+    set_option(&mut tables.par_dev, "TABLE_PAR_DEV")?;
+    if let Ok(excluded) = env::var("EXCLUDED_CLIENTS") {
+        database.schema.excluded_clients.extend(
+            excluded
+                .split(',')
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty()),
+        );
+    }
+    if let Some(subtract) = flag("SUBTRACT_RETURNS")? {
+        database.schema.subtract_returns = Some(subtract);
+    }
+
+    let training = &mut file.training;
+    set(&mut training.schedule, "TRAINING_SCHEDULE")?;
+    set(&mut training.timezone, "TRAINING_TIMEZONE")?;
+    set(&mut training.refit_iterations, "MODEL_REFIT_ITERATIONS")?;
+    set(&mut training.matrix_rebuild_days, "MATRIX_REBUILD_DAYS")?;
+    set(&mut training.registry_keep, "MODEL_REGISTRY_KEEP")?;
+    set(&mut training.max_model_age_hours, "MODEL_MAX_AGE_HOURS")?;
+    if let Ok(precision) = env::var("MODEL_PRECISION") {
+        training.precision = match precision.as_str() {
+            "f32" => Precision::F32,
+            "f64" => Precision::F64,
+            _ => {
+                return Err(format!(
+                    "MODEL_PRECISION must be f32 or f64, not '{}'",
+                    precision
+                ))
+            }
+        };
+    }
+    if let Some(enabled) = flag("MODEL_GATE")? {
+        training.gate.enabled = enabled;
+    }
+    set(
+        &mut training.gate.max_epr_increase,
+        "MODEL_GATE_MAX_EPR_INCREASE",
+    )?;
+    set(
+        &mut training.gate.max_count_drop,
+        "MODEL_GATE_MAX_COUNT_DROP",
+    )?;
+
+    set_option(&mut file.tenants_file, "TENANTS_FILE")?;
+    Ok(())
+}
+
+fn parse<T: FromStr>(name: &str) -> Result<Option<T>, String>
+where
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("{} is not valid ('{}'): {}", name, value, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn set<T: FromStr>(field: &mut T, name: &str) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    if let Some(value) = parse(name)? {
+        *field = value;
+    }
+    Ok(())
+}
+
+fn set_option<T: FromStr>(field: &mut Option<T>, name: &str) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    if let Some(value) = parse(name)? {
+        *field = Some(value);
+    }
+    Ok(())
+}
+
+/// `true`/`1`/`on` or `false`/`0`/`off`
+fn flag(name: &str) -> Result<Option<bool>, String> {
+    match env::var(name).as_deref() {
+        Ok("true" | "1" | "on") => Ok(Some(true)),
+        Ok("false" | "0" | "off") => Ok(Some(false)),
+        Ok(value) => Err(format!("{} must be true or false, not '{}'", name, value)),
+        Err(_) => Ok(None),
+    }
+}
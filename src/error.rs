@@ -0,0 +1,118 @@
+use crate::models::db::DatabaseError;
+use serde_json::json;
+use std::convert::Infallible;
+use tracing::error;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+/// Errors of a request, rejected by the handlers and rendered by [`handle_rejection`]
+#[derive(Debug)]
+pub enum AppError {
+    /// The tenant has no model yet, e.g. while its first training runs
+    ModelNotLoaded,
+    /// The client doesn't exist in the database
+    UnknownClient(String),
+    /// A product, tenant or model version that doesn't exist
+    NotFound(String),
+    /// Invalid parameters or body
+    BadRequest(String),
+    /// No API key, or an unknown one
+    Unauthorized(String),
+    /// The API key lacks the scope of the route
+    Forbidden(String),
+    /// The request conflicts with the current state, e.g. a training run in progress
+    Conflict(String),
+    Database(DatabaseError),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::ModelNotLoaded => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::UnknownClient(_) | AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Database(DatabaseError::NotFound(_)) => StatusCode::NOT_FOUND,
+            AppError::Database(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier of the error for clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::ModelNotLoaded => "model_not_loaded",
+            AppError::UnknownClient(_) => "unknown_client",
+            AppError::NotFound(_) | AppError::Database(DatabaseError::NotFound(_)) => "not_found",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::Conflict(_) => "conflict",
+            AppError::Database(_) => "database_unavailable",
+            AppError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::ModelNotLoaded => write!(f, "No model is loaded yet"),
+            AppError::UnknownClient(id) => write!(f, "Unknown client {}", id),
+            AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            AppError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            AppError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            AppError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            AppError::Conflict(msg) => write!(f, "{}", msg),
+            AppError::Database(DatabaseError::NotFound(msg)) => write!(f, "Not found: {}", msg),
+            AppError::Database(e) => write!(f, "Database unavailable: {}", e),
+            AppError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<DatabaseError> for AppError {
+    fn from(error: DatabaseError) -> Self {
+        AppError::Database(error)
+    }
+}
+
+impl warp::reject::Reject for AppError {}
+
+/// Renders rejections as `{"error": ..., "code": ...}` with the matching status code
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, code, message) = if let Some(error) = rejection.find::<AppError>() {
+        if error.status().is_server_error() {
+            error!("Error handling request: {}", error);
+        }
+        (error.status(), error.code(), error.to_string())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "bad_request", error.to_string())
+    } else if let Some(error) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "bad_request", error.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "Method not allowed".to_string(),
+        )
+    } else {
+        error!("Unhandled rejection: {:?}", rejection);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Internal error".to_string(),
+        )
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "error": message, "code": code })),
+        status,
+    ))
+}
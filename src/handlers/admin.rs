@@ -0,0 +1,132 @@
+use crate::error::AppError;
+use crate::handlers::recommendations::ServerFilter;
+use crate::services::events::TrainingEvent;
+use crate::services::modelserver::SharedModelServer;
+use crate::services::training::HyperparameterGrid;
+use futures::stream::{self, Stream, StreamExt};
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::sse::Event;
+use warp::Filter;
+
+/// Training routes: start a run, follow its progress and cancel it
+pub fn admin_routes(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    start_training(server.clone())
+        .or(training_status(server.clone()))
+        .or(training_events(server.clone()))
+        .or(cancel_training(server))
+}
+
+/// `POST /admin/train`, optionally with a JSON body overriding the hyperparameter grid,
+/// or `POST /admin/train?mode=refit` to warm start from the served model
+fn start_training(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path!("admin" / "train"))
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::bytes())
+        .and_then(|model_server: SharedModelServer, query: HashMap<String, String>, body: Bytes| async move {
+            info!("Received request to start training");
+            let run = if query.get("mode").map(String::as_str) == Some("refit") {
+                model_server.refit()
+            } else {
+                let grid = if body.iter().all(u8::is_ascii_whitespace) {
+                    HyperparameterGrid::default()
+                } else {
+                    serde_json::from_slice::<HyperparameterGrid>(&body).map_err(|e| {
+                        warp::reject::custom(AppError::BadRequest(format!(
+                            "invalid hyperparameters: {}",
+                            e
+                        )))
+                    })?
+                };
+                model_server.train(grid)
+            };
+            match run {
+                Ok(run) => Ok(warp::reply::with_status(
+                    warp::reply::json(&run.status()),
+                    StatusCode::ACCEPTED,
+                )),
+                Err(e) => Err(warp::reject::custom(e)),
+            }
+        })
+}
+
+fn training_status(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path!("admin" / "train" / "status"))
+        .and(warp::get())
+        .and_then(|model_server: SharedModelServer| async move {
+            match model_server.training_status() {
+                Some(status) => Ok(warp::reply::json(&status)),
+                None => Err(warp::reject::custom(AppError::NotFound(
+                    "no training run yet".to_string(),
+                ))),
+            }
+        })
+}
+
+/// `GET /admin/train/events`: Server-Sent Events with the progress of the training
+/// runs, starting with the status of the current or last one
+fn training_events(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path!("admin" / "train" / "events"))
+        .and(warp::get())
+        .map(|model_server: SharedModelServer| {
+            info!("Client subscribed to training events");
+            let receiver = model_server.training_events().subscribe();
+            let status = model_server
+                .training_status()
+                .map(|status| Event::default().event("status").json_data(&status));
+            let events = stream::iter(status).chain(event_stream(receiver));
+            warp::sse::reply(warp::sse::keep_alive().stream(events))
+        })
+}
+
+fn event_stream(
+    receiver: tokio::sync::broadcast::Receiver<TrainingEvent>,
+) -> impl Stream<Item = Result<Event, serde_json::Error>> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let sse = Event::default().event(event.name()).json_data(&event);
+                    return Some((sse, receiver));
+                }
+                // A slow subscriber skips the events it missed
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Training events subscriber skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+fn cancel_training(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path!("admin" / "train" / "cancel"))
+        .and(warp::post())
+        .and_then(|model_server: SharedModelServer| async move {
+            info!("Received request to cancel training");
+            match model_server.cancel_training() {
+                Some(status) => Ok(warp::reply::json(&status)),
+                None => Err(warp::reject::custom(AppError::NotFound(
+                    "no training run in progress".to_string(),
+                ))),
+            }
+        })
+}
@@ -0,0 +1,65 @@
+use crate::handlers::recommendations::ServerFilter;
+use crate::services::metrics;
+use crate::services::modelserver::SharedModelServer;
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::Filter;
+
+/// Probes for orchestrators, open even when the API needs keys
+pub fn probe_routes(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    healthz(server.clone()).or(readyz(server))
+}
+
+/// `GET /healthz`: the process is up and answering
+fn healthz(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path!("healthz"))
+        .and(warp::get())
+        .map(|_: SharedModelServer| warp::reply::json(&json!({ "status": "ok" })))
+}
+
+/// `GET /readyz`: 200 when a recent enough model is loaded and the database answers,
+/// 503 with the failed checks otherwise
+fn readyz(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server.and(warp::path!("readyz")).and(warp::get()).then(
+        |model_server: SharedModelServer| async move {
+            let readiness = model_server.readiness().await;
+            let status = if readiness.ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(warp::reply::json(&readiness), status)
+        },
+    )
+}
+
+/// `GET /status`: served model, last training run and database latency
+pub fn status_handler(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path!("status"))
+        .and(warp::get())
+        .then(|model_server: SharedModelServer| async move {
+            warp::reply::json(&model_server.status().await)
+        })
+}
+
+/// `GET /metrics`: every tenant's metrics in the Prometheus text format
+pub fn metrics_handler(
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).map(|| {
+        warp::reply::with_header(
+            metrics::render(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+    })
+}
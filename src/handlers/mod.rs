@@ -1 +1,4 @@
+pub mod admin;
+pub mod health;
+pub mod models;
 pub mod recommendations;
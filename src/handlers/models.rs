@@ -0,0 +1,64 @@
+use crate::handlers::recommendations::ServerFilter;
+use crate::services::modelserver::SharedModelServer;
+use tracing::info;
+use warp::Filter;
+
+/// Registry routes: list the kept model versions, activate one and roll back
+pub fn model_routes(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    list_models(server.clone())
+        .or(activate_model(server.clone()))
+        .or(rollback_model(server))
+}
+
+fn list_models(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path("models"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(|model_server: SharedModelServer| async move {
+            info!("Received request for model versions");
+            match model_server.list_versions().await {
+                Ok(versions) => Ok(warp::reply::json(&versions)),
+                Err(e) => Err(warp::reject::custom(e)),
+            }
+        })
+}
+
+fn activate_model(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path("models"))
+        .and(warp::path::param::<u64>())
+        .and(warp::path("activate"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(|model_server: SharedModelServer, version: u64| async move {
+            info!("Received request to activate model version {}", version);
+            match model_server.activate_version(version).await {
+                Ok(version) => Ok(warp::reply::json(&version)),
+                Err(e) => Err(warp::reject::custom(e)),
+            }
+        })
+}
+
+fn rollback_model(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path("models"))
+        .and(warp::path("rollback"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(|model_server: SharedModelServer| async move {
+            info!("Received request to roll back the model");
+            match model_server.rollback().await {
+                Ok(version) => Ok(warp::reply::json(&version)),
+                Err(e) => Err(warp::reject::custom(e)),
+            }
+        })
+}
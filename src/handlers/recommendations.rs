@@ -1,186 +1,278 @@
-use crate::MODEL_SERVER;
+use crate::auth::{self, ApiKeys, Scope};
+use crate::error::AppError;
+use crate::handlers::admin::admin_routes;
+use crate::handlers::health::{metrics_handler, probe_routes, status_handler};
+use crate::handlers::models::model_routes;
+use crate::logging;
+use crate::services::modelserver::{BatchRequest, SharedModelServer};
 use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::info;
+use warp::filters::BoxedFilter;
 use warp::Filter;
 
+/// Extracts the model server of the tenant a request is addressed to
+pub(crate) type ServerFilter = BoxedFilter<(SharedModelServer,)>;
+
+/// Routes of every tenant under `/t/{tenant}`, and of `default` without the prefix,
+/// plus the process-wide `/metrics`
 pub fn global_handler(
+    tenants: Arc<HashMap<String, SharedModelServer>>,
+    default: SharedModelServer,
+    keys: Option<Arc<ApiKeys>>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let default_server = warp::any().and_then(move || {
+        let default = default.clone();
+        async move { Ok::<_, warp::Rejection>(default) }
+    });
+    let tenant_server =
+        warp::path("t")
+            .and(warp::path::param::<String>())
+            .and_then(move |tenant: String| {
+                let tenants = tenants.clone();
+                async move {
+                    let tenant = percent_decode_str(&tenant).decode_utf8_lossy().to_string();
+                    match tenants.get(&tenant) {
+                        Some(model_server) => Ok(model_server.clone()),
+                        None => Err(warp::reject::custom(AppError::NotFound(format!(
+                            "tenant {}",
+                            tenant
+                        )))),
+                    }
+                }
+            });
+
+    metrics_handler()
+        .or(routes(tenant_server.boxed(), keys.clone()))
+        .or(routes(default_server.boxed(), keys))
+}
+
+/// All routes, answered by the model server extracted by `server`. The probes are
+/// open; the rest need a key with the `read` or `admin` scope when `keys` is set.
+fn routes(
+    server: ServerFilter,
+    keys: Option<Arc<ApiKeys>>,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    get_recommendation_with_limit()
-        .or(get_recommendation())
-        .or(metadata_handler())
-        .or(clients_handler())
-        .or(products_handler())
-        .or(get_client_by_id())
-        .or(get_product_by_id())
+    let read = recommend_batch(server.clone())
+        .or(get_recommendation_with_limit(server.clone()))
+        .or(get_recommendation(server.clone()))
+        .or(metadata_handler(server.clone()))
+        .or(clients_handler(server.clone()))
+        .or(products_handler(server.clone()))
+        .or(get_client_by_id(server.clone()))
+        .or(get_product_by_id(server.clone()))
+        .or(status_handler(server.clone()));
+    let admin = model_routes(server.clone()).or(admin_routes(server.clone()));
+
+    probe_routes(server)
+        .or(auth::require(keys.clone(), Scope::Read).and(read))
+        .or(auth::require(keys, Scope::Admin).and(admin))
 }
 
 fn metadata_handler(
+    server: ServerFilter,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("metadata").and_then(|| async move {
-        println!("Received request for metadata");
-        let model_server = MODEL_SERVER.lock().await;
-        let metadata = model_server.as_ref().unwrap().get_metadata().await;
-        Result::<_, warp::Rejection>::Ok(warp::reply::json(&metadata))
-    })
+    server
+        .and(warp::path("metadata"))
+        .and_then(|model_server: SharedModelServer| async move {
+            info!("Received request for metadata");
+            let metadata = model_server.get_metadata().await;
+            Result::<_, warp::Rejection>::Ok(warp::reply::json(&metadata))
+        })
 }
 
 fn clients_handler(
+    server: ServerFilter,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("clients")
+    server
+        .and(warp::path("clients"))
         .and(warp::query::<std::collections::HashMap<String, String>>())
         .and_then(
-            |query: std::collections::HashMap<String, String>| async move {
+            |model_server: SharedModelServer,
+             query: std::collections::HashMap<String, String>| async move {
                 let search = query.get("search").cloned().unwrap_or_default();
-                let page = query
-                    .get("page")
-                    .and_then(|p| p.parse::<i64>().ok())
-                    .unwrap_or(1);
-                println!(
+                let page = parse_page(query.get("page"))?;
+                info!(
                     "Received request for clients with search: {} and page: {}",
                     search, page
                 );
-                let model_server = MODEL_SERVER.lock().await;
-                match model_server
-                    .as_ref()
-                    .unwrap()
-                    .get_clients(search, page)
-                    .await
-                {
-                    Ok(client_page) => Ok(warp::reply::json(&client_page)),
-                    Err(_) => Err(warp::reject::not_found()),
+                match model_server.get_clients(search, page).await {
+                    Ok(client_page) => {
+                        // The page carries the names and emails of its clients
+                        for client in &client_page.clients {
+                            auth::audit("clients", &client.id);
+                        }
+                        Ok(warp::reply::json(&client_page))
+                    }
+                    Err(e) => Err(warp::reject::custom(AppError::from(e))),
                 }
             },
         )
 }
 
 fn products_handler(
+    server: ServerFilter,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("products")
+    server
+        .and(warp::path("products"))
         .and(warp::query::<std::collections::HashMap<String, String>>())
         .and_then(
-            |query: std::collections::HashMap<String, String>| async move {
+            |model_server: SharedModelServer,
+             query: std::collections::HashMap<String, String>| async move {
                 let search = query.get("search").cloned().unwrap_or_default();
-                let page = query
-                    .get("page")
-                    .and_then(|p| p.parse::<i64>().ok())
-                    .unwrap_or(1);
-                println!(
+                let page = parse_page(query.get("page"))?;
+                info!(
                     "Received request for products with search: {} and page: {}",
                     search, page
                 );
-                let model_server = MODEL_SERVER.lock().await;
-                match model_server
-                    .as_ref()
-                    .unwrap()
-                    .get_products(search, page)
-                    .await
-                {
+                match model_server.get_products(search, page).await {
                     Ok(product_page) => Ok(warp::reply::json(&product_page)),
-                    Err(e) => {
-                        eprintln!("Error getting products: {:?}", e);
-                        Err(warp::reject::not_found())
-                    }
+                    Err(e) => Err(warp::reject::custom(AppError::from(e))),
                 }
             },
         )
 }
 
 fn get_recommendation(
+    server: ServerFilter,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("recommend")
+    server
+        .and(warp::path("recommend"))
         .and(warp::path::param::<String>())
-        .and_then(|client_id: String| async move {
-            println!(
-                "Received request for recommendations for client_id: {}",
-                client_id
-            );
-            let decoded_client_id = percent_decode_str(&client_id)
-                .decode_utf8_lossy()
-                .to_string();
-            let model_server = MODEL_SERVER.lock().await;
-            match model_server
-                .as_ref()
-                .unwrap()
-                .predict(decoded_client_id.as_str(), None)
-                .await
-            {
-                Some(recommendations) => Ok(warp::reply::json(&recommendations)),
-                None => Err(warp::reject::not_found()),
-            }
-        })
+        .and(warp::path::end())
+        .and_then(
+            |model_server: SharedModelServer, client_id: String| async move {
+                let decoded_client_id = percent_decode_str(&client_id)
+                    .decode_utf8_lossy()
+                    .to_string();
+                info!(
+                    "Received request for recommendations for client_id: {}",
+                    logging::client(&decoded_client_id)
+                );
+                auth::audit("recommend", &decoded_client_id);
+                match model_server.predict(decoded_client_id.as_str(), None).await {
+                    Ok(recommendations) => Ok(warp::reply::json(&recommendations)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
+}
+
+/// `POST /recommend/batch` with `{"clients": [...], "n": 10, "exclude_purchased": true,
+/// "filters": {"include": [...], "exclude": [...]}}`
+fn recommend_batch(
+    server: ServerFilter,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    server
+        .and(warp::path!("recommend" / "batch"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and_then(
+            |model_server: SharedModelServer, request: BatchRequest| async move {
+                info!(
+                    "Received request for batch recommendations for {} clients",
+                    request.clients.len()
+                );
+                for client_id in &request.clients {
+                    auth::audit("recommend", client_id);
+                }
+                match model_server.recommend_batch(request).await {
+                    Ok(response) => Ok(warp::reply::json(&response)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
 }
 
 fn get_recommendation_with_limit(
+    server: ServerFilter,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("recommend")
+    server
+        .and(warp::path("recommend"))
         .and(warp::path::param::<String>())
         .and(warp::path::param::<i64>())
-        .and_then(|client_id: String, limit: i64| async move {
-            println!(
-                "Received request for recommendations for client_id: {} with limit: {}",
-                client_id, limit
-            );
-            let decoded_client_id = percent_decode_str(&client_id)
-                .decode_utf8_lossy()
-                .to_string();
-            let model_server = MODEL_SERVER.lock().await;
-            match model_server
-                .as_ref()
-                .unwrap()
-                .predict(decoded_client_id.as_str(), Some(limit as usize))
-                .await
-            {
-                Some(recommendations) => Ok(warp::reply::json(&recommendations)),
-                None => Err(warp::reject::not_found()),
-            }
-        })
+        .and_then(
+            |model_server: SharedModelServer, client_id: String, limit: i64| async move {
+                let decoded_client_id = percent_decode_str(&client_id)
+                    .decode_utf8_lossy()
+                    .to_string();
+                info!(
+                    "Received request for recommendations for client_id: {} with limit: {}",
+                    logging::client(&decoded_client_id),
+                    limit
+                );
+                auth::audit("recommend", &decoded_client_id);
+                if limit < 1 {
+                    return Err(warp::reject::custom(AppError::BadRequest(format!(
+                        "the limit must be at least 1, not {}",
+                        limit
+                    ))));
+                }
+                match model_server
+                    .predict(decoded_client_id.as_str(), Some(limit as usize))
+                    .await
+                {
+                    Ok(recommendations) => Ok(warp::reply::json(&recommendations)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
 }
 
 fn get_client_by_id(
+    server: ServerFilter,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("client")
+    server
+        .and(warp::path("client"))
         .and(warp::path::param::<String>())
-        .and_then(|client_id: String| async move {
-            println!("Received request for client_id: {}", client_id);
-            let decoded_client_id = percent_decode_str(&client_id)
-                .decode_utf8_lossy()
-                .to_string();
-            let model_server = MODEL_SERVER.lock().await;
-            match model_server
-                .as_ref()
-                .unwrap()
-                .get_client_by_id(decoded_client_id)
-                .await
-            {
-                Ok(client) => Ok(warp::reply::json(&client)),
-                Err(e) => {
-                    eprintln!("Error getting products: {:?}", e);
-                    Err(warp::reject::not_found())
+        .and_then(
+            |model_server: SharedModelServer, client_id: String| async move {
+                let decoded_client_id = percent_decode_str(&client_id)
+                    .decode_utf8_lossy()
+                    .to_string();
+                info!(
+                    "Received request for client_id: {}",
+                    logging::client(&decoded_client_id)
+                );
+                auth::audit("client", &decoded_client_id);
+                match model_server.get_client_by_id(decoded_client_id).await {
+                    Ok(client) => Ok(warp::reply::json(&client)),
+                    Err(e) => Err(warp::reject::custom(AppError::from(e))),
                 }
-            }
-        })
+            },
+        )
 }
 
 fn get_product_by_id(
+    server: ServerFilter,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("product")
+    server
+        .and(warp::path("product"))
         .and(warp::path::param::<String>())
-        .and_then(|product_id: String| async move {
-            println!("Received request for product_id: {}", product_id);
-            let decoded_product_id = percent_decode_str(&product_id)
-                .decode_utf8_lossy()
-                .to_string();
-            let model_server = MODEL_SERVER.lock().await;
-            match model_server
-                .as_ref()
-                .unwrap()
-                .get_product_by_id(decoded_product_id)
-                .await
-            {
-                Ok(product) => Ok(warp::reply::json(&product)),
-                Err(e) => {
-                    eprintln!("Error getting product: {:?}", e);
-                    Err(warp::reject::not_found())
+        .and_then(
+            |model_server: SharedModelServer, product_id: String| async move {
+                info!("Received request for product_id: {}", product_id);
+                let decoded_product_id = percent_decode_str(&product_id)
+                    .decode_utf8_lossy()
+                    .to_string();
+                match model_server.get_product_by_id(decoded_product_id).await {
+                    Ok(product) => Ok(warp::reply::json(&product)),
+                    Err(e) => Err(warp::reject::custom(AppError::from(e))),
                 }
-            }
-        })
+            },
+        )
+}
+
+/// `page` query parameter, 1 when missing
+fn parse_page(page: Option<&String>) -> Result<i64, warp::Rejection> {
+    match page.map(|p| p.parse::<i64>()) {
+        None => Ok(1),
+        Some(Ok(page)) if page >= 1 => Ok(page),
+        _ => Err(warp::reject::custom(AppError::BadRequest(format!(
+            "invalid page '{}'",
+            page.map(String::as_str).unwrap_or_default()
+        )))),
+    }
 }
@@ -0,0 +1,92 @@
+use crate::config::{ClientIdLogging, LogFormat, LoggingConfig};
+use crate::services::metrics::route_label;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::OnceLock;
+use tracing::{info_span, Span};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+static CLIENT_IDS: OnceLock<(ClientIdLogging, String)> = OnceLock::new();
+
+/// Installs the global subscriber, writing to stderr so the output of the commands
+/// stays clean
+pub fn init(config: &LoggingConfig) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.level)
+            .map_err(|e| format!("Invalid log level '{}': {}", config.level, e))?,
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match config.format {
+        LogFormat::Text => builder.try_init()?,
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init()?,
+    }
+    let _ = CLIENT_IDS.set((config.client_ids, config.client_id_salt.clone()));
+    Ok(())
+}
+
+/// A client id as it may appear in the logs: as is, hashed or redacted
+pub struct ClientId<'a> {
+    id: &'a str,
+    redact: bool,
+}
+
+pub fn client(id: &str) -> ClientId<'_> {
+    ClientId { id, redact: true }
+}
+
+/// A client id for the `audit` target, which must say who was accessed: redacted ids
+/// are logged as their salted hash instead
+pub fn audited_client(id: &str) -> ClientId<'_> {
+    ClientId { id, redact: false }
+}
+
+impl fmt::Display for ClientId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match CLIENT_IDS.get() {
+            Some((ClientIdLogging::Redact, _)) if self.redact => f.write_str("[redacted]"),
+            Some((ClientIdLogging::Hash | ClientIdLogging::Redact, salt)) => {
+                let digest = Sha256::new()
+                    .chain_update(salt)
+                    .chain_update(self.id)
+                    .finalize();
+                digest[..6]
+                    .iter()
+                    .try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            _ => f.write_str(self.id),
+        }
+    }
+}
+
+/// Span of an HTTP request, identified by its `X-Request-Id` header or a new id. The
+/// path is reduced to its route, so client ids stay out of it; `key` is filled in
+/// once the API key is checked.
+pub fn request_span(info: warp::trace::Info) -> Span {
+    let request_id = info
+        .request_headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let tenant = info
+        .path()
+        .strip_prefix("/t/")
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        id = %request_id,
+        method = %info.method(),
+        route = %route_label(info.path()),
+        tenant = %tenant,
+        key = tracing::field::Empty,
+    )
+}
@@ -1,73 +1,113 @@
-use env_logger;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
+use error::handle_rejection;
 use handlers::recommendations::global_handler;
 use services::cronjobs::schedule_jobs;
+use services::metrics;
+use services::modelfile::{self, ModelFileOptions};
 use services::modelserver::ModelServer;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::signal;
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+use warp::Filter;
 
+pub mod auth;
+mod cli;
+pub mod config;
+pub mod error;
 pub mod handlers;
+pub mod logging;
 pub mod models;
 pub mod services;
 
-lazy_static::lazy_static! {
-    pub static ref MODEL_SERVER: Arc<Mutex<Option<ModelServer>>> = ModelServer::new("./data/hyperparameters.json");
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok();
-    env_logger::init();
 
+    let cli = Cli::parse();
+    let config = Config::load(&cli.overrides())?;
+    logging::init(&config.logging).map_err(|e| e.to_string())?;
+    modelfile::configure(ModelFileOptions {
+        precision: config.training.precision,
+    });
+    match cli.command {
+        None | Some(Command::Serve) => serve(config).await,
+        Some(command) => cli::run(command, cli.tenant.as_deref(), &config.tenants)
+            .await
+            .map_err(|e| e.to_string().into()),
+    }
+}
+
+/// Serves every tenant and runs their scheduled trainings until Ctrl+C
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let tenants_config = config.tenants;
     // Create a Notify instance for cancellation
     let notify = Arc::new(Notify::new());
 
-    // Schedule jobs
-    let job_handle = {
-        let notify = notify.clone();
-        tokio::spawn(async move {
-            if let Err(e) = schedule_jobs(notify).await {
-                eprintln!("Failed to schedule jobs: {}", e);
-            }
-        })
-    };
+    let mut tenants = HashMap::new();
+    for tenant in &tenants_config.tenants {
+        info!("Initializing tenant '{}'", tenant.name);
+        let model_server = ModelServer::start(tenant, &config.catalog, notify.clone())
+            .await
+            .map_err(|e| format!("Tenant '{}': {}", tenant.name, e))?;
+        tenants.insert(tenant.name.clone(), model_server);
+    }
+    let default = tenants[&tenants_config.default].clone();
 
-    MODEL_SERVER
-        .lock()
-        .await
-        .as_mut()
-        .unwrap()
-        .initialize(notify.clone())
+    // Schedule jobs
+    let jobs = tenants_config
+        .tenants
+        .iter()
+        .map(|tenant| (tenant.clone(), tenants[&tenant.name].clone()))
+        .collect();
+    let mut scheduler = schedule_jobs(jobs)
         .await
-        .unwrap();
+        .map_err(|e| format!("Failed to schedule jobs: {}", e))?;
+
+    if config.auth.is_none() {
+        warn!("No API keys file configured, the API is open to anyone");
+    }
 
     // Create the Warp filters
-    let routes = global_handler();
+    let tenants = Arc::new(tenants);
+    let routes = global_handler(tenants.clone(), default, config.auth.clone())
+        .recover(handle_rejection)
+        .with(warp::log::custom(metrics::record_request))
+        .with(warp::trace(logging::request_span));
 
     // Start the Warp server
-    let (addr, server) =
-        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], 3030), async {
+    let (addr, server) = warp::serve(routes)
+        .try_bind_with_graceful_shutdown(config.server.addr(), async {
             signal::ctrl_c()
                 .await
                 .expect("Failed to listen for ctrl_c signal");
-        });
+        })
+        .map_err(|e| format!("Failed to listen on {}: {}", config.server.addr(), e))?;
 
-    println!("Server running on http://{}", addr);
+    info!("Server running on http://{}", addr);
 
     tokio::select! {
         _ = server => {
-            println!("Server has shut down");
+            info!("Server has shut down");
         }
         _ = signal::ctrl_c() => {
-            println!("Received Ctrl+C, shutting down");
+            info!("Received Ctrl+C, shutting down");
             notify.notify_waiters(); // Signal cancellation
         }
     }
 
-    // Cancel the scheduled jobs
-    job_handle.abort();
+    // Cancel the scheduled jobs and the training runs in progress
+    if let Err(e) = scheduler.shutdown().await {
+        error!("Failed to stop the scheduled jobs: {}", e);
+    }
+    for server in tenants.values() {
+        server.cancel_training();
+    }
 
-    println!("Application has shut down");
+    info!("Application has shut down");
 
     Ok(())
 }
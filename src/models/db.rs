@@ -1,11 +1,20 @@
+use crate::models::schema::SchemaMapping;
+use crate::services::files::FileDatabase;
+use crate::services::firebird::FirebirdDatabase;
+use crate::services::metrics;
+use crate::services::mssql::SqlServerDatabase;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{info, instrument, warn};
+
 pub type ClientProductMatrix = HashMap<String, HashMap<String, f64>>;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ClientRow {
     pub id: String,
     pub name: String,
@@ -19,7 +28,7 @@ pub struct ClientPage {
     pub clients: Vec<ClientRow>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ProductRow {
     pub id: String,
     pub description: String,
@@ -33,12 +42,71 @@ pub struct ProductPage {
     pub products: Vec<ProductRow>,
 }
 
+/// Position of the latest invoice included in an extraction, ordered by invoice date
+/// and then document number
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Watermark {
+    pub date: String,
+    pub document: String,
+    /// Day the watermark was taken, bounding the scan for cancellations in the next delta
+    #[serde(default)]
+    pub extracted_on: Option<String>,
+}
+
+/// Interactions posted or cancelled between two extractions
+pub struct MatrixDelta {
+    pub added: ClientProductMatrix,
+    /// Interactions of previously extracted invoices that were cancelled, by document.
+    /// On a whole extraction, the invoices already cancelled, which `added` leaves out.
+    pub cancelled: HashMap<String, ClientProductMatrix>,
+}
+
+/// Subtracts `returned` quantities from the interactions in `matrix`, clamping at
+/// zero, and returns how many interactions were adjusted. Returns of interactions the
+/// matrix doesn't have (e.g. of excluded or unextracted invoices) are ignored.
+pub fn subtract_interactions(
+    matrix: &mut ClientProductMatrix,
+    returned: ClientProductMatrix,
+) -> usize {
+    let mut adjusted = 0;
+    for (client_id, products) in returned {
+        let Some(client_products) = matrix.get_mut(&client_id) else {
+            continue;
+        };
+        for (product_id, quantity) in products {
+            if let Some(total) = client_products.get_mut(&product_id) {
+                *total = (*total - quantity).max(0.0);
+                adjusted += 1;
+            }
+        }
+    }
+    adjusted
+}
+
 #[async_trait]
 pub trait DatabaseTrait {
     async fn build_client_product_matrix(
         &mut self,
     ) -> Result<ClientProductMatrix, Box<dyn std::error::Error>>;
+    /// Latest invoice position, or `None` when the backend can't extract incrementally
+    async fn get_watermark(&mut self) -> Result<Option<Watermark>, Box<dyn std::error::Error>>;
+    /// Interactions of invoices after `since` up to `until`. Without `since`, the whole
+    /// matrix up to `until`, including clients and products without interactions, and
+    /// the invoices cancelled so far.
+    async fn fetch_matrix_delta(
+        &mut self,
+        since: Option<&Watermark>,
+        until: &Watermark,
+    ) -> Result<MatrixDelta, Box<dyn std::error::Error>>;
+    /// Quantities of return documents and credit notes dated on or after `since` (or
+    /// all of them), by document. Empty when return subtraction is disabled.
+    async fn fetch_returns(
+        &mut self,
+        since: Option<&str>,
+    ) -> Result<HashMap<String, ClientProductMatrix>, Box<dyn std::error::Error>>;
     async fn close(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+    /// Runs a trivial query to check the connection is alive
+    async fn ping(&mut self) -> Result<(), Box<dyn std::error::Error>>;
     async fn get_clients(
         &mut self,
         search: String,
@@ -58,16 +126,135 @@ pub trait DatabaseTrait {
         &mut self,
         id: String,
     ) -> Result<ProductRow, Box<dyn std::error::Error>>;
+    /// Every client and product the search can return, for the catalog cache
+    async fn get_all_clients(&mut self) -> Result<Vec<ClientRow>, Box<dyn std::error::Error>>;
+    async fn get_all_products(&mut self) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>>;
+    /// The products among `ids` that exist, in no particular order
+    async fn get_products_by_ids(
+        &mut self,
+        ids: Vec<String>,
+    ) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>>;
+}
+
+type Backend = Box<dyn DatabaseTrait + Send + Sync>;
+
+/// A connection of the pool, reopened on its next use after failing with a
+/// connection error, so the pool recovers from a database restart
+struct PooledBackend {
+    backend: Mutex<Backend>,
+    broken: AtomicBool,
+}
+
+impl PooledBackend {
+    fn new(backend: Backend) -> Self {
+        PooledBackend {
+            backend: Mutex::new(backend),
+            broken: AtomicBool::new(false),
+        }
+    }
+}
+
+/// A connection taken from the pool for one call
+struct Connection<'a> {
+    backend: MutexGuard<'a, Backend>,
+    broken: &'a AtomicBool,
+}
+
+impl Connection<'_> {
+    /// Passes `result` on, marking the connection to be reopened when it failed with a
+    /// connection error
+    fn check<T>(&self, result: Result<T, DatabaseError>) -> Result<T, DatabaseError> {
+        if let Err(DatabaseError::ConnectionError(_)) = &result {
+            self.broken.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl Deref for Connection<'_> {
+    type Target = dyn DatabaseTrait + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &**self.backend
+    }
+}
+
+impl DerefMut for Connection<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut **self.backend
+    }
 }
 
+/// Pool of connections to a tenant's backend. Each call takes an idle connection, so
+/// concurrent requests don't wait on each other.
 pub struct Database {
-    backend: Arc<Mutex<dyn DatabaseTrait + Send + Sync>>,
+    backends: Vec<PooledBackend>,
+    next: AtomicUsize,
+    settings: DatabaseSettings,
+}
+
+/// Connection settings and schema mapping of the backend a tenant reads from
+#[derive(Debug, Clone)]
+pub struct DatabaseSettings {
+    pub db_type: String,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub name: Option<String>,
+    pub files_dir: Option<String>,
+    pub schema: SchemaMapping,
+    /// Connections opened by the model server to answer requests
+    pub pool_size: usize,
+}
+
+impl DatabaseSettings {
+    /// Checks the backend is supported and has what it needs to connect
+    pub fn validate(&self) -> Result<(), String> {
+        match self.db_type.as_str() {
+            "sqlserver" | "firebird" => {
+                required(&self.host, "host")?;
+                required(&self.port, "port")?;
+                required(&self.username, "username")?;
+                required(&self.password, "password")?;
+                required(&self.name, "name")?;
+            }
+            "files" => {
+                required(&self.files_dir, "files_dir")?;
+            }
+            "" => return Err("database type is not set (DB_TYPE or database.type)".to_string()),
+            db_type => {
+                return Err(format!(
+                    "unsupported database type '{}', expected sqlserver, firebird or files",
+                    db_type
+                ))
+            }
+        }
+        if self.pool_size == 0 {
+            return Err("database pool size must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A connection setting the backend can't work without
+pub(crate) fn required<'a, T>(value: &'a Option<T>, name: &str) -> Result<&'a T, String> {
+    value.as_ref().ok_or_else(|| {
+        format!(
+            "database {} is not set (DB_{} or database.{})",
+            name,
+            name.to_uppercase(),
+            name
+        )
+    })
 }
 
 #[derive(Debug)]
 pub enum DatabaseError {
     ConnectionError(String),
     CloseError(String),
+    /// The client or product doesn't exist
+    NotFound(String),
 }
 
 impl std::fmt::Display for DatabaseError {
@@ -75,67 +262,279 @@ impl std::fmt::Display for DatabaseError {
         match self {
             DatabaseError::ConnectionError(msg) => write!(f, "Connection Error: {}", msg),
             DatabaseError::CloseError(msg) => write!(f, "Close Error: {}", msg),
+            DatabaseError::NotFound(msg) => write!(f, "Not Found: {}", msg),
         }
     }
 }
 
 impl std::error::Error for DatabaseError {}
 
+/// Keeps the `DatabaseError`s a backend raises (e.g. `NotFound`) and reports anything
+/// else as a connection error
+fn backend_error(context: &str, error: Box<dyn std::error::Error>) -> DatabaseError {
+    match error.downcast::<DatabaseError>() {
+        Ok(error) => *error,
+        Err(error) => DatabaseError::ConnectionError(format!("{}: {}", context, error)),
+    }
+}
+
 impl Database {
-    pub fn new(backend: Arc<Mutex<dyn DatabaseTrait + Send + Sync>>) -> Self {
-        Database { backend }
+    /// Single connection, for training jobs and tools
+    pub async fn connect(settings: &DatabaseSettings) -> Result<Self, DatabaseError> {
+        Ok(Database {
+            backends: vec![PooledBackend::new(Self::connect_backend(settings).await?)],
+            next: AtomicUsize::new(0),
+            settings: settings.clone(),
+        })
+    }
+
+    /// `settings.pool_size` connections; data dumps are read once and shared
+    pub async fn connect_pool(settings: &DatabaseSettings) -> Result<Self, DatabaseError> {
+        let size = if settings.db_type == "files" {
+            1
+        } else {
+            settings.pool_size.max(1)
+        };
+        let mut backends = Vec::with_capacity(size);
+        for _ in 0..size {
+            backends.push(PooledBackend::new(Self::connect_backend(settings).await?));
+        }
+        Ok(Database {
+            backends,
+            next: AtomicUsize::new(0),
+            settings: settings.clone(),
+        })
     }
 
-    pub async fn build_matrix(&mut self) -> Result<ClientProductMatrix, DatabaseError> {
-        let mut backend = self.backend.lock().await;
-        let matrix = backend
+    async fn connect_backend(settings: &DatabaseSettings) -> Result<Backend, DatabaseError> {
+        settings
+            .validate()
+            .map_err(DatabaseError::ConnectionError)?;
+        let backend: Backend = match settings.db_type.as_str() {
+            "sqlserver" => Box::new(SqlServerDatabase::new(settings).await?),
+            "firebird" => Box::new(FirebirdDatabase::new(settings)?),
+            _ => Box::new(FileDatabase::new(settings)?),
+        };
+        Ok(backend)
+    }
+
+    /// An idle connection, or the next one in turn when all are busy. A connection
+    /// that failed last time is reopened first.
+    async fn backend(&self) -> Connection<'_> {
+        let idle = self.backends.iter().find_map(|pooled| {
+            let backend = pooled.backend.try_lock().ok()?;
+            Some((pooled, backend))
+        });
+        let (pooled, backend) = match idle {
+            Some(idle) => idle,
+            None => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed) % self.backends.len();
+                let pooled = &self.backends[next];
+                (pooled, pooled.backend.lock().await)
+            }
+        };
+        let mut connection = Connection {
+            backend,
+            broken: &pooled.broken,
+        };
+        // Data dumps are read once, there is no connection to lose
+        if connection.broken.load(Ordering::Relaxed) && self.settings.db_type != "files" {
+            match Self::connect_backend(&self.settings).await {
+                Ok(backend) => {
+                    *connection.backend = backend;
+                    connection.broken.store(false, Ordering::Relaxed);
+                    info!("Reconnected to the database");
+                }
+                Err(e) => warn!("Failed to reconnect to the database: {}", e),
+            }
+        }
+        connection
+    }
+
+    #[instrument(name = "db", skip_all, fields(method = "build_matrix"))]
+    pub async fn build_matrix(&self) -> Result<ClientProductMatrix, DatabaseError> {
+        let mut backend = self.backend().await;
+        let timer = metrics::db_timer("build_client_product_matrix");
+        let result = backend
             .build_client_product_matrix()
             .await
-            .map_err(|e| DatabaseError::ConnectionError(format!("Error building matrix: {}", e)))?;
+            .map_err(|e| DatabaseError::ConnectionError(format!("Error building matrix: {}", e)));
+        let mut matrix = backend.check(result)?;
+        drop(timer);
+
+        let timer = metrics::db_timer("fetch_returns");
+        let result = backend
+            .fetch_returns(None)
+            .await
+            .map_err(|e| DatabaseError::ConnectionError(format!("Error fetching returns: {}", e)));
+        let returns = backend.check(result)?;
+        drop(timer);
+        if !returns.is_empty() {
+            let adjusted = returns
+                .into_values()
+                .map(|returned| subtract_interactions(&mut matrix, returned))
+                .sum::<usize>();
+            info!("Subtracted returns from {} interactions", adjusted);
+        }
         Ok(matrix)
     }
+
+    #[instrument(name = "db", skip_all, fields(method = "fetch_returns"))]
+    pub async fn fetch_returns(
+        &self,
+        since: Option<&str>,
+    ) -> Result<HashMap<String, ClientProductMatrix>, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("fetch_returns");
+        let result = backend
+            .fetch_returns(since)
+            .await
+            .map_err(|e| DatabaseError::ConnectionError(format!("Error fetching returns: {}", e)));
+        backend.check(result)
+    }
+
+    #[instrument(name = "db", skip_all, fields(method = "get_watermark"))]
+    pub async fn get_watermark(&self) -> Result<Option<Watermark>, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_watermark");
+        let result = backend
+            .get_watermark()
+            .await
+            .map_err(|e| DatabaseError::ConnectionError(format!("Error getting watermark: {}", e)));
+        backend.check(result)
+    }
+
+    #[instrument(name = "db", skip_all, fields(method = "fetch_matrix_delta"))]
+    pub async fn fetch_matrix_delta(
+        &self,
+        since: Option<&Watermark>,
+        until: &Watermark,
+    ) -> Result<MatrixDelta, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("fetch_matrix_delta");
+        let result = backend.fetch_matrix_delta(since, until).await.map_err(|e| {
+            DatabaseError::ConnectionError(format!("Error fetching matrix delta: {}", e))
+        });
+        backend.check(result)
+    }
+
+    #[instrument(name = "db", skip_all, fields(method = "get_clients"))]
     pub async fn get_clients(
-        &mut self,
+        &self,
         search: String,
         page: i64,
     ) -> Result<ClientPage, DatabaseError> {
-        let mut backend = self.backend.lock().await;
-        backend
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_clients");
+        let result = backend
             .get_clients(search, page)
             .await
-            .map_err(|e| DatabaseError::ConnectionError(format!("Error getting clients: {}", e)))
+            .map_err(|e| DatabaseError::ConnectionError(format!("Error getting clients: {}", e)));
+        backend.check(result)
     }
 
+    #[instrument(name = "db", skip_all, fields(method = "get_products"))]
     pub async fn get_products(
-        &mut self,
+        &self,
         search: String,
         page: i64,
     ) -> Result<ProductPage, DatabaseError> {
-        let mut backend = self.backend.lock().await;
-        backend
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_products");
+        let result = backend
             .get_products(search, page)
             .await
-            .map_err(|e| DatabaseError::ConnectionError(format!("Error getting products: {}", e)))
+            .map_err(|e| DatabaseError::ConnectionError(format!("Error getting products: {}", e)));
+        backend.check(result)
     }
 
-    pub async fn get_client_by_id(&mut self, id: String) -> Result<ClientRow, DatabaseError> {
-        let mut backend = self.backend.lock().await;
-        backend
+    #[instrument(name = "db", skip_all, fields(method = "get_client_by_id"))]
+    pub async fn get_client_by_id(&self, id: String) -> Result<ClientRow, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_client_by_id");
+        let result = backend
             .get_client_by_id(id)
             .await
-            .map_err(|e| DatabaseError::ConnectionError(format!("Error getting client: {}", e)))
+            .map_err(|e| backend_error("Error getting client", e));
+        backend.check(result)
     }
 
-    pub async fn get_product_by_id(&mut self, id: String) -> Result<ProductRow, DatabaseError> {
-        let mut backend = self.backend.lock().await;
-        backend
+    #[instrument(name = "db", skip_all, fields(method = "get_product_by_id"))]
+    pub async fn get_product_by_id(&self, id: String) -> Result<ProductRow, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_product_by_id");
+        let result = backend
             .get_product_by_id(id)
             .await
-            .map_err(|e| DatabaseError::ConnectionError(format!("Error getting product: {}", e)))
+            .map_err(|e| backend_error("Error getting product", e));
+        backend.check(result)
+    }
+
+    /// Every client the searches can return, for the catalog cache
+    #[instrument(name = "db", skip_all, fields(method = "get_all_clients"))]
+    pub async fn get_all_clients(&self) -> Result<Vec<ClientRow>, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_all_clients");
+        let result = backend
+            .get_all_clients()
+            .await
+            .map_err(|e| backend_error("Error getting clients", e));
+        backend.check(result)
+    }
+
+    /// Every product the searches can return, for the catalog cache
+    #[instrument(name = "db", skip_all, fields(method = "get_all_products"))]
+    pub async fn get_all_products(&self) -> Result<Vec<ProductRow>, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_all_products");
+        let result = backend
+            .get_all_products()
+            .await
+            .map_err(|e| backend_error("Error getting products", e));
+        backend.check(result)
+    }
+
+    /// The products among `ids` that exist, split in as many queries as the backend
+    /// needs
+    #[instrument(name = "db", skip_all, fields(method = "get_products_by_ids"))]
+    pub async fn get_products_by_ids(
+        &self,
+        ids: Vec<String>,
+    ) -> Result<Vec<ProductRow>, DatabaseError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("get_products_by_ids");
+        let result = backend
+            .get_products_by_ids(ids)
+            .await
+            .map_err(|e| backend_error("Error getting products", e));
+        backend.check(result)
+    }
+
+    /// Round trip of a trivial query on one of the connections
+    #[instrument(name = "db", skip_all, fields(method = "ping"))]
+    pub async fn ping(&self) -> Result<Duration, DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("ping");
+        let start = Instant::now();
+        let result = backend
+            .ping()
+            .await
+            .map_err(|e| DatabaseError::ConnectionError(format!("Error pinging database: {}", e)));
+        backend.check(result)?;
+        Ok(start.elapsed())
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.backends.len()
     }
 
-    pub async fn close(&mut self) -> Result<(), DatabaseError> {
-        let mut backend = self.backend.lock().await;
+    #[instrument(name = "db", skip_all, fields(method = "close"))]
+    pub async fn close(&self) -> Result<(), DatabaseError> {
+        let mut backend = self.backend().await;
+        let _timer = metrics::db_timer("close");
         backend
             .close()
             .await
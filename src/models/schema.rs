@@ -0,0 +1,276 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// Describes where the ERP keeps invoices, clients and products, so the SQL backends
+/// can extract interactions from schemas other than Aspel SAE. Every field defaults to
+/// the Aspel SAE layout, so a mapping file only needs to list what differs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchemaMapping {
+    /// Schema used to qualify table names on SQL Server (e.g. `dbo`)
+    pub schema: Option<String>,
+    pub invoices: InvoiceTable,
+    pub invoice_lines: InvoiceLineTable,
+    pub clients: ClientTable,
+    pub products: ProductTable,
+    pub returns: ReturnsMapping,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InvoiceTable {
+    pub table: String,
+    pub document: String,
+    pub client: String,
+    /// Needed for incremental extraction; without it the matrix is rebuilt every run
+    pub date: Option<String>,
+    /// When set, only invoices cancelled since the last extraction are scanned
+    pub cancel_date: Option<String>,
+    pub status: Option<String>,
+    /// Invoices with any of these statuses (e.g. cancelled) are ignored
+    pub excluded_statuses: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InvoiceLineTable {
+    pub table: String,
+    pub document: String,
+    pub product: String,
+    pub quantity: String,
+}
+
+/// Return documents and credit notes whose quantities are subtracted from the
+/// interactions, so goods sent back don't count as purchases
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReturnsMapping {
+    pub enabled: bool,
+    pub documents: Vec<ReturnDocuments>,
+}
+
+/// Header and line tables of one kind of return document. They share the invoice
+/// layout; `headers.date` bounds the scan for new returns and `headers.status` with
+/// `excluded_statuses` leaves out cancelled returns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReturnDocuments {
+    pub headers: InvoiceTable,
+    pub lines: InvoiceLineTable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientTable {
+    pub table: String,
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    /// Client ids left out of training and listings
+    pub excluded_ids: Vec<String>,
+    /// `LIKE` patterns on the client name left out of training and listings
+    pub excluded_name_patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProductTable {
+    pub table: String,
+    pub id: String,
+    pub description: String,
+    pub price: String,
+    pub status: Option<String>,
+    /// Only products with one of these statuses are recommended
+    pub active_statuses: Vec<String>,
+}
+
+impl Default for SchemaMapping {
+    fn default() -> Self {
+        SchemaMapping {
+            schema: Some("dbo".to_string()),
+            invoices: InvoiceTable::default(),
+            invoice_lines: InvoiceLineTable::default(),
+            clients: ClientTable::default(),
+            products: ProductTable::default(),
+            returns: ReturnsMapping::default(),
+        }
+    }
+}
+
+impl Default for InvoiceTable {
+    fn default() -> Self {
+        InvoiceTable {
+            table: "FACTF01".to_string(),
+            document: "CVE_DOC".to_string(),
+            client: "CVE_CLPV".to_string(),
+            date: Some("FECHA_DOC".to_string()),
+            cancel_date: Some("FECHA_CANCELA".to_string()),
+            status: Some("STATUS".to_string()),
+            excluded_statuses: vec!["C".to_string()],
+        }
+    }
+}
+
+impl Default for InvoiceLineTable {
+    fn default() -> Self {
+        InvoiceLineTable {
+            table: "PAR_FACTF01".to_string(),
+            document: "CVE_DOC".to_string(),
+            product: "CVE_ART".to_string(),
+            quantity: "CANT".to_string(),
+        }
+    }
+}
+
+impl Default for ReturnsMapping {
+    fn default() -> Self {
+        ReturnsMapping {
+            enabled: false,
+            documents: vec![ReturnDocuments::default()],
+        }
+    }
+}
+
+impl Default for ReturnDocuments {
+    fn default() -> Self {
+        ReturnDocuments {
+            headers: InvoiceTable {
+                table: "FACTD01".to_string(),
+                cancel_date: None,
+                ..InvoiceTable::default()
+            },
+            lines: InvoiceLineTable {
+                table: "PAR_FACTD01".to_string(),
+                ..InvoiceLineTable::default()
+            },
+        }
+    }
+}
+
+impl Default for ClientTable {
+    fn default() -> Self {
+        ClientTable {
+            table: "CLIE01".to_string(),
+            id: "CLAVE".to_string(),
+            name: "NOMBRE".to_string(),
+            email: "EMAILPRED".to_string(),
+            excluded_ids: Vec::new(),
+            excluded_name_patterns: vec!["%PUBLICO EN GENERAL%".to_string()],
+        }
+    }
+}
+
+impl Default for ProductTable {
+    fn default() -> Self {
+        ProductTable {
+            table: "INVE01".to_string(),
+            id: "CVE_ART".to_string(),
+            description: "DESCR".to_string(),
+            price: "ULT_COSTO".to_string(),
+            status: Some("STATUS".to_string()),
+            active_statuses: vec!["A".to_string()],
+        }
+    }
+}
+
+/// Table names replacing the Aspel SAE defaults when there is no mapping file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TableOverrides {
+    pub fact: Option<String>,
+    pub par_fact: Option<String>,
+    pub client: Option<String>,
+    pub inve: Option<String>,
+    pub dev: Option<String>,
+    pub par_dev: Option<String>,
+}
+
+/// Adjustments applied on top of the mapping, from the configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SchemaOverrides {
+    pub tables: TableOverrides,
+    /// Added to the client exclusions
+    pub excluded_clients: Vec<String>,
+    /// Turns return subtraction on or off
+    pub subtract_returns: Option<bool>,
+}
+
+impl SchemaMapping {
+    /// Loads the mapping from the TOML file at `file`, or falls back to the Aspel SAE
+    /// layout with the table names in `overrides`. The excluded clients and return
+    /// subtraction of `overrides` apply in both cases.
+    pub fn load(
+        file: Option<&str>,
+        overrides: &SchemaOverrides,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut mapping = match file {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read schema file {}: {}", path, e))?;
+                toml::from_str::<SchemaMapping>(&contents)
+                    .map_err(|e| format!("Invalid schema file {}: {}", path, e))?
+            }
+            None => {
+                let mut mapping = SchemaMapping::default();
+                let tables = &overrides.tables;
+                for (table, name) in [
+                    (&mut mapping.invoices.table, &tables.fact),
+                    (&mut mapping.invoice_lines.table, &tables.par_fact),
+                    (&mut mapping.clients.table, &tables.client),
+                    (&mut mapping.products.table, &tables.inve),
+                ] {
+                    if let Some(name) = name {
+                        *table = name.clone();
+                    }
+                }
+                let returns = &mut mapping.returns.documents[0];
+                for (table, name) in [
+                    (&mut returns.headers.table, &tables.dev),
+                    (&mut returns.lines.table, &tables.par_dev),
+                ] {
+                    if let Some(name) = name {
+                        *table = name.clone();
+                    }
+                }
+                mapping
+            }
+        };
+
+        mapping
+            .clients
+            .excluded_ids
+            .extend(overrides.excluded_clients.iter().cloned());
+        if let Some(subtract) = overrides.subtract_returns {
+            mapping.returns.enabled = subtract;
+        }
+        Ok(mapping)
+    }
+
+    /// Points every table at another company. Aspel SAE suffixes table names with the
+    /// company number (`FACTF01`, `FACTF02`, ...), so the trailing digits of each table
+    /// name are replaced by `company`, zero-padded to as many digits as they had.
+    pub fn set_company(&mut self, company: &str) -> Result<(), String> {
+        if company.is_empty() || !company.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("company must be a number, not '{}'", company));
+        }
+        let mut tables = vec![
+            &mut self.invoices.table,
+            &mut self.invoice_lines.table,
+            &mut self.clients.table,
+            &mut self.products.table,
+        ];
+        for documents in &mut self.returns.documents {
+            tables.push(&mut documents.headers.table);
+            tables.push(&mut documents.lines.table);
+        }
+        for table in tables {
+            let base = table
+                .trim_end_matches(|c: char| c.is_ascii_digit())
+                .to_string();
+            let width = table.len() - base.len();
+            *table = format!("{}{:0>width$}", base, company, width = width);
+        }
+        Ok(())
+    }
+}
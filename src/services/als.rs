@@ -1,12 +1,56 @@
+use crate::logging;
 use crate::models::db::ClientProductMatrix;
+use crate::services::events::{TrainingEvent, TrainingEvents};
+use crate::services::training::{Hyperparameters, JSONData};
 use futures::FutureExt;
 use ndarray::{s, Array1, Array2, Axis};
 use ndarray_linalg::Solve;
 use ndarray_rand::{rand_distr::Uniform, RandomExt};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
 use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// How a batch of recommendations is chosen
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RecommendOptions {
+    /// Products per client
+    pub n: usize,
+    /// Leaves out the products each client already bought
+    pub exclude_purchased: bool,
+    pub filters: ProductFilters,
+}
+
+impl Default for RecommendOptions {
+    fn default() -> Self {
+        RecommendOptions {
+            n: 10,
+            exclude_purchased: false,
+            filters: ProductFilters::default(),
+        }
+    }
+}
+
+/// Products a batch may recommend
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProductFilters {
+    /// Only these products, when set
+    pub include: Option<HashSet<String>>,
+    pub exclude: HashSet<String>,
+}
+
+impl ProductFilters {
+    fn allows(&self, product_id: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.contains(product_id))
+            && !self.exclude.contains(product_id)
+    }
+}
 
 pub struct ALS {
     pub num_factors: usize,
@@ -20,6 +64,8 @@ pub struct ALS {
     pub product_factors: Option<Array2<f64>>,
     pub client_index: Option<HashMap<String, usize>>,
     pub product_index: Option<HashMap<String, usize>>,
+    /// Model whose factors `fit` starts from, instead of random ones
+    pub initial_model: Option<Arc<JSONData>>,
 }
 
 impl ALS {
@@ -42,9 +88,66 @@ impl ALS {
             product_factors: None,
             client_index: None,
             product_index: None,
+            initial_model: None,
         }
     }
 
+    /// Starts `fit` from the factors of `model`, matched by id; clients and products it
+    /// doesn't know start random. Ignored if the number of factors differs.
+    pub fn warm_start(&mut self, model: Arc<JSONData>) {
+        if model.hyperparameters.num_factors != self.num_factors {
+            warn!(
+                "Can't warm start from a model with {} factors, training from scratch",
+                model.hyperparameters.num_factors
+            );
+            return;
+        }
+        self.initial_model = Some(model);
+    }
+
+    /// Copies the factors of the initial model over the rows of the ids it knows
+    fn seed_factors(&self, client_factors: &mut Array2<f64>, product_factors: &mut Array2<f64>) {
+        let (Some(model), Some(client_index), Some(product_index)) =
+            (&self.initial_model, &self.client_index, &self.product_index)
+        else {
+            return;
+        };
+        let seed = |factors: &mut Array2<f64>,
+                    index: &HashMap<String, usize>,
+                    initial_index: &HashMap<String, usize>,
+                    initial_factors: &[Vec<f64>]| {
+            let mut reused = 0;
+            for (id, &row) in index {
+                if let Some(initial) = initial_index.get(id).and_then(|&i| initial_factors.get(i)) {
+                    factors
+                        .row_mut(row)
+                        .assign(&Array1::from_vec(initial.clone()));
+                    reused += 1;
+                }
+            }
+            reused
+        };
+        let clients = seed(
+            client_factors,
+            client_index,
+            &model.client_index,
+            &model.client_factors,
+        );
+        let products = seed(
+            product_factors,
+            product_index,
+            &model.product_index,
+            &model.product_factors,
+        );
+        info!(
+            "Warm start: reused {}/{} client and {}/{} product factors",
+            clients,
+            client_index.len(),
+            products,
+            product_index.len()
+        );
+    }
+
     fn build_rating_matrix(&mut self) -> Array2<f64> {
         let clients: HashSet<_> = self.matrix.keys().collect();
         let products: HashSet<_> = self.matrix.values().flat_map(|p| p.keys()).collect();
@@ -81,7 +184,8 @@ impl ALS {
         weighted_matrix
     }
 
-    pub fn fit(&mut self, notify: Arc<Notify>) {
+    /// Trains the factors; `events` receives the loss after each iteration
+    pub fn fit(&mut self, notify: Arc<Notify>, events: Option<&TrainingEvents>) {
         let rating_matrix = self.build_rating_matrix();
         let weighted_matrix = self.create_weight_matrix(&rating_matrix);
 
@@ -92,15 +196,16 @@ impl ALS {
             Array2::<f64>::random((num_clients, self.num_factors), Uniform::new(0.0, 1.0));
         let mut product_factors =
             Array2::<f64>::random((num_products, self.num_factors), Uniform::new(0.0, 1.0));
+        self.seed_factors(&mut client_factors, &mut product_factors);
 
-        for _ in 0..self.max_iterations {
-            if notify.notified().now_or_never().is_some() {
+        for iteration in 0..self.max_iterations {
+            if cancelled(&notify) {
                 break;
             }
 
             // Fix product_factors and solve for client_factors
             for i in 0..num_clients {
-                if notify.notified().now_or_never().is_some() {
+                if cancelled(&notify) {
                     break;
                 }
 
@@ -126,7 +231,7 @@ impl ALS {
 
             // Fix client_factors and solve for product_factors
             for j in 0..num_products {
-                if notify.notified().now_or_never().is_some() {
+                if cancelled(&notify) {
                     break;
                 }
 
@@ -149,11 +254,45 @@ impl ALS {
                     .slice_mut(s![j, ..])
                     .assign(&lhs.solve_into(rhs).unwrap());
             }
+
+            if let Some(events) = events.filter(|events| events.has_subscribers()) {
+                events.publish(TrainingEvent::Iteration {
+                    hyperparameters: Hyperparameters {
+                        num_factors: self.num_factors,
+                        regularization: self.regularization,
+                        confidence_multiplier: self.confidence_multiplier,
+                    },
+                    iteration: iteration + 1,
+                    loss: self.loss(&client_factors, &product_factors),
+                });
+            }
         }
         self.client_factors = Some(client_factors);
         self.product_factors = Some(product_factors);
     }
 
+    /// Regularized loss on the observed interactions only, Σ c_ui (1 − x_u·y_i)² with
+    /// confidence c_ui = 1 + α r_ui, so it costs one dot product per purchase
+    fn loss(&self, client_factors: &Array2<f64>, product_factors: &Array2<f64>) -> f64 {
+        let (Some(client_index), Some(product_index)) = (&self.client_index, &self.product_index)
+        else {
+            return 0.0;
+        };
+        let mut error = 0.0;
+        for (client, products) in &self.matrix {
+            let client_vector = client_factors.row(client_index[client]);
+            for (product, &quantity) in products.iter().filter(|(_, &q)| q > 0.0) {
+                let prediction = product_factors
+                    .row(product_index[product])
+                    .dot(&client_vector);
+                let confidence = 1.0 + self.confidence_multiplier * quantity;
+                error += confidence * (1.0 - prediction).powi(2);
+            }
+        }
+        let penalty = client_factors.mapv(|f| f * f).sum() + product_factors.mapv(|f| f * f).sum();
+        error + self.regularization * penalty
+    }
+
     pub fn recommend(&self, client_id: &str, n: Option<usize>) -> Vec<String> {
         if let (
             Some(ref client_factors),
@@ -186,13 +325,82 @@ impl ALS {
                     .map(|(product_id, _)| product_id)
                     .collect();
             }
-            log::warn!("Client ID not found: {}", client_id);
+            warn!("Client ID not found: {}", logging::client(client_id));
             return Vec::new();
         }
-        log::warn!("Model not trained yet");
+        warn!("Model not trained yet");
         Vec::new()
     }
 
+    /// Top products of each client, scored with a single matrix product; `None` for
+    /// the clients the model doesn't know
+    pub fn recommend_batch(
+        &self,
+        client_ids: &[String],
+        options: &RecommendOptions,
+    ) -> Vec<Option<Vec<String>>> {
+        let mut results = vec![None; client_ids.len()];
+        let (Some(client_factors), Some(product_factors), Some(client_index), Some(product_index)) = (
+            &self.client_factors,
+            &self.product_factors,
+            &self.client_index,
+            &self.product_index,
+        ) else {
+            warn!("Model not trained yet");
+            return results;
+        };
+        let known: Vec<(usize, usize)> = client_ids
+            .iter()
+            .enumerate()
+            .filter_map(|(i, id)| client_index.get(id).map(|&row| (i, row)))
+            .collect();
+        if known.is_empty() {
+            return results;
+        }
+
+        let rows: Vec<usize> = known.iter().map(|&(_, row)| row).collect();
+        let scores = client_factors
+            .select(Axis(0), &rows)
+            .dot(&product_factors.t());
+        // Product id of each column of `scores`
+        let mut product_ids = vec![""; product_factors.nrows()];
+        for (id, &idx) in product_index {
+            product_ids[idx] = id.as_str();
+        }
+        let allowed: Vec<bool> = product_ids
+            .iter()
+            .map(|id| options.filters.allows(id))
+            .collect();
+
+        for (&(i, _), row) in known.iter().zip(scores.outer_iter()) {
+            let purchased = options
+                .exclude_purchased
+                .then(|| self.matrix.get(&client_ids[i]))
+                .flatten();
+            let mut candidates: Vec<(usize, f64)> = row
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| {
+                    allowed[j]
+                        && !purchased.is_some_and(|bought| bought.contains_key(product_ids[j]))
+                })
+                .map(|(j, &score)| (j, score))
+                .collect();
+            if options.n < candidates.len() {
+                candidates.select_nth_unstable_by(options.n, |a, b| b.1.total_cmp(&a.1));
+                candidates.truncate(options.n);
+            }
+            candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+            results[i] = Some(
+                candidates
+                    .into_iter()
+                    .map(|(j, _)| product_ids[j].to_string())
+                    .collect(),
+            );
+        }
+        results
+    }
+
     pub fn compute_epr(&self) -> Option<f64> {
         if let (
             Some(ref client_factors),
@@ -275,3 +483,13 @@ impl ALS {
         self.product_index = Some(product_index.clone());
     }
 }
+
+/// Whether cancellation was requested. The permit is stored again, so the loops
+/// above and the other fits sharing `notify` stop as well.
+fn cancelled(notify: &Notify) -> bool {
+    if notify.notified().now_or_never().is_some() {
+        notify.notify_one();
+        return true;
+    }
+    false
+}
@@ -0,0 +1,128 @@
+use crate::models::db::{ClientPage, ClientRow, Database, DatabaseError, ProductPage, ProductRow};
+use crate::services::files::{paginate, total_pages};
+use crate::services::search::SearchIndex;
+use arc_swap::ArcSwapOption;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The clients and products of a tenant as of one load, sorted by id so equally
+/// relevant matches come in the order of the database pages
+struct CatalogData {
+    clients: Vec<ClientRow>,
+    products: Vec<ProductRow>,
+    client_index: HashMap<String, usize>,
+    product_index: HashMap<String, usize>,
+    /// Client names and product descriptions, indexed in the order of the rows
+    client_search: SearchIndex,
+    product_search: SearchIndex,
+    loaded_at: DateTime<Utc>,
+}
+
+impl CatalogData {
+    fn new(mut clients: Vec<ClientRow>, mut products: Vec<ProductRow>) -> Self {
+        clients.sort_by(|a, b| a.id.cmp(&b.id));
+        products.sort_by(|a, b| a.id.cmp(&b.id));
+        CatalogData {
+            client_index: index(clients.iter().map(|client| &client.id)),
+            product_index: index(products.iter().map(|product| &product.id)),
+            client_search: SearchIndex::new(clients.iter().map(|client| client.name.as_str())),
+            product_search: SearchIndex::new(
+                products.iter().map(|product| product.description.as_str()),
+            ),
+            clients,
+            products,
+            loaded_at: Utc::now(),
+        }
+    }
+}
+
+fn index<'a>(ids: impl Iterator<Item = &'a String>) -> HashMap<String, usize> {
+    ids.enumerate().map(|(i, id)| (id.clone(), i)).collect()
+}
+
+/// Rows of `rows` matching `search`, best first
+fn matching<'a, T>(rows: &'a [T], index: &SearchIndex, search: &str) -> Vec<&'a T> {
+    index.search(search).into_iter().map(|i| &rows[i]).collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct CatalogStatus {
+    pub clients: usize,
+    pub products: usize,
+    pub loaded_at: DateTime<Utc>,
+    pub age_secs: i64,
+}
+
+/// In-memory copy of the clients and products, swapped whole on every reload so
+/// requests never see half a catalog. Empty until the first load succeeds; callers
+/// go to the database whenever it has no answer.
+#[derive(Default)]
+pub struct Catalog {
+    data: ArcSwapOption<CatalogData>,
+}
+
+impl Catalog {
+    /// Reads every client and product, keeping the previous catalog if that fails
+    pub async fn load(&self, db: &Database) -> Result<(), DatabaseError> {
+        let clients = db.get_all_clients().await?;
+        let products = db.get_all_products().await?;
+        self.data
+            .store(Some(Arc::new(CatalogData::new(clients, products))));
+        Ok(())
+    }
+
+    pub fn client(&self, id: &str) -> Option<ClientRow> {
+        let data = self.data.load();
+        let data = data.as_ref()?;
+        data.client_index.get(id).map(|&i| data.clients[i].clone())
+    }
+
+    pub fn product(&self, id: &str) -> Option<ProductRow> {
+        let data = self.data.load();
+        let data = data.as_ref()?;
+        data.product_index
+            .get(id)
+            .map(|&i| data.products[i].clone())
+    }
+
+    /// Page of the clients matching `search` regardless of accents, case and small
+    /// typos, the most relevant first; `None` before the first load
+    pub fn search_clients(&self, search: &str, page: i64) -> Option<ClientPage> {
+        let data = self.data.load_full()?;
+        let matches = matching(&data.clients, &data.client_search, search);
+        Some(ClientPage {
+            current_page: page,
+            total_pages: total_pages(matches.len()),
+            clients: paginate(&matches, page)
+                .iter()
+                .map(|&client| client.clone())
+                .collect(),
+        })
+    }
+
+    /// Page of the products matching `search` like [`Catalog::search_clients`]
+    pub fn search_products(&self, search: &str, page: i64) -> Option<ProductPage> {
+        let data = self.data.load_full()?;
+        let matches = matching(&data.products, &data.product_search, search);
+        Some(ProductPage {
+            current_page: page,
+            total_pages: total_pages(matches.len()),
+            products: paginate(&matches, page)
+                .iter()
+                .map(|&product| product.clone())
+                .collect(),
+        })
+    }
+
+    pub fn status(&self) -> Option<CatalogStatus> {
+        let data = self.data.load_full()?;
+        Some(CatalogStatus {
+            clients: data.clients.len(),
+            products: data.products.len(),
+            loaded_at: data.loaded_at,
+            age_secs: (Utc::now() - data.loaded_at).num_seconds(),
+        })
+    }
+}
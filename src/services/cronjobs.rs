@@ -1,50 +1,70 @@
-use super::mssql::SqlServerDatabase;
-use crate::models::db::{Database, DatabaseTrait};
-use crate::services::firebird::FirebirdDatabase;
-use crate::services::training::find_best_als_model;
-use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use crate::services::modelserver::SharedModelServer;
+use crate::services::schedule::JobMode;
+use crate::services::tenants::Tenant;
+use crate::services::training::HyperparameterGrid;
+use chrono::Utc;
 use tokio_cron_scheduler::{JobBuilder, JobScheduler};
+use tracing::{info, warn};
 
-pub async fn schedule_jobs(notify: Arc<Notify>) -> Result<(), Box<dyn std::error::Error>> {
+/// Schedules the training jobs of every tenant. A job doesn't start during a blackout
+/// window, nor while another run of the same tenant is in progress.
+pub async fn schedule_jobs(
+    tenants: Vec<(Tenant, SharedModelServer)>,
+) -> Result<JobScheduler, Box<dyn std::error::Error>> {
     let sched = JobScheduler::new().await?;
-    let job = JobBuilder::new()
-        .with_timezone(chrono_tz::Mexico::General)
-        .with_cron_job_type()
-        .with_schedule("0 0 0 * * *")
-        .unwrap()
-        .with_run_async(Box::new(move |_, _| {
-            let job_notify = notify.clone();
-            Box::pin(async move {
-                println!("Executing model training and update job");
-                let db_type =
-                    std::env::var("DB_TYPE").expect("DB_TYPE is not set in the environment");
+    for (tenant, server) in tenants {
+        for job in &tenant.schedule.jobs {
+            let tenant = tenant.clone();
+            let server = server.clone();
+            let training_job = job.clone();
+            let job = JobBuilder::new()
+                .with_timezone(tenant.schedule.timezone)
+                .with_cron_job_type()
+                .with_schedule(job.cron.as_str())
+                .map_err(|e| {
+                    format!(
+                        "Invalid schedule {} for job '{}' of tenant '{}': {:?}",
+                        job.cron, job.name, tenant.name, e
+                    )
+                })?
+                .with_run_async(Box::new(move |_, _| {
+                    let tenant = tenant.clone();
+                    let server = server.clone();
+                    let job = training_job.clone();
+                    Box::pin(async move {
+                        if let Some(blackout) = tenant.schedule.blackout_at(&Utc::now()) {
+                            info!(
+                                "Skipping job '{}' for tenant '{}': blackout from {} to {}",
+                                job.name, tenant.name, blackout.start, blackout.end
+                            );
+                            return;
+                        }
+                        info!("Executing job '{}' for tenant '{}'", job.name, tenant.name);
+                        let started = match job.mode {
+                            JobMode::Search => server.train(HyperparameterGrid::default()),
+                            JobMode::Refit if server.has_model() => server.refit(),
+                            JobMode::Refit => {
+                                info!("No model to refit, running a full search instead");
+                                server.train(HyperparameterGrid::default())
+                            }
+                        };
+                        match started {
+                            Ok(_) => info!(
+                                "Job '{}' started training for tenant '{}'",
+                                job.name, tenant.name
+                            ),
+                            Err(e) => warn!(
+                                "Job '{}' skipped for tenant '{}': {}",
+                                job.name, tenant.name, e
+                            ),
+                        }
+                    })
+                }))
+                .build()?;
 
-                let db: Arc<Mutex<dyn DatabaseTrait + Send + Sync>> = match db_type.as_str() {
-                    // TODO Add support for Firebird
-                    "firebird" => Arc::new(Mutex::new(FirebirdDatabase::new())),
-                    "sqlserver" => Arc::new(Mutex::new(SqlServerDatabase::new().await)),
-                    _ => {
-                        eprintln!("Unsupported DB_TYPE: '{}'", db_type);
-                        return;
-                    }
-                };
-                let matrix = match Database::new(db).build_matrix().await {
-                    Ok(matrix) => matrix,
-                    Err(e) => {
-                        eprintln!("Failed to build matrix: {}", e);
-                        return;
-                    }
-                };
-
-                find_best_als_model(matrix, job_notify.clone()).await;
-                println!("Model training and update job executed");
-            })
-        }))
-        .build()
-        .unwrap();
-
-    sched.add(job).await?;
+            sched.add(job).await?;
+        }
+    }
     sched.start().await?;
-    Ok(())
+    Ok(sched)
 }
@@ -0,0 +1,83 @@
+use crate::services::training::{Hyperparameters, TrainingState};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events kept for slow subscribers before they start missing some
+const CAPACITY: usize = 1024;
+
+/// Progress of a training run, as streamed to the dashboard
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TrainingEvent {
+    Started {
+        total: usize,
+    },
+    CombinationStarted {
+        hyperparameters: Hyperparameters,
+    },
+    /// Loss after each ALS iteration, on the observed interactions
+    Iteration {
+        hyperparameters: Hyperparameters,
+        iteration: usize,
+        loss: f64,
+    },
+    CombinationFinished {
+        hyperparameters: Hyperparameters,
+        epr: f64,
+        processed: usize,
+        total: usize,
+    },
+    BestSoFar {
+        hyperparameters: Hyperparameters,
+        epr: f64,
+    },
+    Finished {
+        state: TrainingState,
+        message: String,
+    },
+}
+
+impl TrainingEvent {
+    /// SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrainingEvent::Started { .. } => "started",
+            TrainingEvent::CombinationStarted { .. } => "combination_started",
+            TrainingEvent::Iteration { .. } => "iteration",
+            TrainingEvent::CombinationFinished { .. } => "combination_finished",
+            TrainingEvent::BestSoFar { .. } => "best_so_far",
+            TrainingEvent::Finished { .. } => "finished",
+        }
+    }
+}
+
+/// Broadcasts the training events of a tenant to every subscriber. Publishing never
+/// blocks training: without subscribers events are dropped, and subscribers that
+/// fall behind skip the oldest ones.
+#[derive(Clone)]
+pub struct TrainingEvents {
+    sender: broadcast::Sender<TrainingEvent>,
+}
+
+impl Default for TrainingEvents {
+    fn default() -> Self {
+        TrainingEvents {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl TrainingEvents {
+    pub fn publish(&self, event: TrainingEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Whether anyone listens, so costly events like the loss can be skipped
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TrainingEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,468 @@
+use crate::models::db::{
+    required, ClientPage, ClientProductMatrix, ClientRow, DatabaseError, DatabaseSettings,
+    DatabaseTrait, MatrixDelta, ProductPage, ProductRow, Watermark,
+};
+use crate::services::search::fold;
+use async_trait::async_trait;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const PAGE_SIZE: usize = 10;
+
+#[derive(Deserialize)]
+struct InteractionRecord {
+    #[serde(alias = "client")]
+    client_id: String,
+    #[serde(alias = "product")]
+    product_id: String,
+    #[serde(alias = "qty", deserialize_with = "number_or_string")]
+    quantity: f64,
+    /// ISO 8601 date, needed for incremental extraction
+    #[serde(default)]
+    date: Option<String>,
+    /// Invoice number, ordering interactions within the same date
+    #[serde(default)]
+    document: Option<String>,
+}
+
+impl InteractionRecord {
+    /// Identifies a row without a document by its contents, so it keeps its key when
+    /// the dump is exported again in another order. Identical rows share the key and
+    /// are subtracted together.
+    fn content_key(&self) -> String {
+        let digest = Sha256::new()
+            .chain_update(self.date.as_deref().unwrap_or_default())
+            .chain_update([0])
+            .chain_update(&self.client_id)
+            .chain_update([0])
+            .chain_update(&self.product_id)
+            .chain_update([0])
+            .chain_update(self.quantity.to_le_bytes())
+            .finalize();
+        let hex: String = digest[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("row {}", hex)
+    }
+
+    fn is_after(&self, watermark: &Watermark) -> bool {
+        let date = self.date.as_deref().unwrap_or_default();
+        let document = self.document.as_deref().unwrap_or_default();
+        (date, document) > (watermark.date.as_str(), watermark.document.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientRecord {
+    #[serde(alias = "client_id")]
+    id: String,
+    name: String,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProductRecord {
+    #[serde(alias = "product_id")]
+    id: String,
+    #[serde(alias = "name")]
+    description: String,
+    #[serde(default, deserialize_with = "number_or_string")]
+    price: f64,
+}
+
+/// Reads interactions and the client/product catalog from `interactions`, `clients`
+/// and `products` files (Parquet or CSV) in `FILES_DIR`, so the model can be trained
+/// and served from a data dump without database access. Returns are read from an
+/// optional `returns` file laid out like `interactions`.
+pub struct FileDatabase {
+    dir: PathBuf,
+    excluded_clients: Vec<String>,
+    subtract_returns: bool,
+    clients: Vec<ClientRow>,
+    products: Vec<ProductRow>,
+}
+
+impl FileDatabase {
+    pub fn new(settings: &DatabaseSettings) -> Result<Self, DatabaseError> {
+        let dir = PathBuf::from(
+            required(&settings.files_dir, "files_dir").map_err(DatabaseError::ConnectionError)?,
+        );
+        let mut database = FileDatabase {
+            dir,
+            excluded_clients: settings.schema.clients.excluded_ids.clone(),
+            subtract_returns: settings.schema.returns.enabled,
+            clients: Vec::new(),
+            products: Vec::new(),
+        };
+        database.load_catalog().map_err(|e| {
+            DatabaseError::ConnectionError(format!(
+                "Failed to load clients and products files: {}",
+                e
+            ))
+        })?;
+        Ok(database)
+    }
+
+    fn load_catalog(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut clients: Vec<ClientRow> = read_records::<ClientRecord>(&self.dir, "clients")?
+            .into_iter()
+            .map(|record| ClientRow {
+                id: record.id,
+                name: record.name,
+                email: record.email.unwrap_or("unknown_email".to_string()),
+            })
+            .collect();
+        clients.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut products: Vec<ProductRow> = read_records::<ProductRecord>(&self.dir, "products")?
+            .into_iter()
+            .map(|record| ProductRow {
+                id: record.id,
+                description: record.description,
+                price: record.price,
+            })
+            .collect();
+        products.sort_by(|a, b| a.id.cmp(&b.id));
+
+        self.clients = clients;
+        self.products = products;
+        Ok(())
+    }
+
+    fn aggregate<'a>(
+        &self,
+        interactions: impl Iterator<Item = &'a InteractionRecord>,
+    ) -> ClientProductMatrix {
+        let mut matrix: ClientProductMatrix = HashMap::new();
+        for interaction in interactions {
+            if self.excluded_clients.contains(&interaction.client_id) {
+                continue;
+            }
+            *matrix
+                .entry(interaction.client_id.clone())
+                .or_default()
+                .entry(interaction.product_id.clone())
+                .or_insert(0.0) += interaction.quantity;
+        }
+        matrix
+    }
+
+    /// Adds every client and product to the matrix, so they are indexed by the model
+    /// even without interactions
+    fn add_catalog(&self, matrix: &mut ClientProductMatrix) {
+        for client in &self.clients {
+            matrix.entry(client.id.clone()).or_default();
+        }
+        for product in &self.products {
+            if let Some(client_products) = matrix.values_mut().next() {
+                client_products.entry(product.id.clone()).or_insert(0.0);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DatabaseTrait for FileDatabase {
+    async fn build_client_product_matrix(
+        &mut self,
+    ) -> Result<ClientProductMatrix, Box<dyn std::error::Error>> {
+        // Pick up new dumps dropped into the directory since the last run
+        self.load_catalog()?;
+        let interactions = read_records::<InteractionRecord>(&self.dir, "interactions")?;
+
+        let mut first_date: Option<&str> = None;
+        let mut last_date: Option<&str> = None;
+        for date in interactions.iter().filter_map(|i| i.date.as_deref()) {
+            first_date = Some(first_date.map_or(date, |d| d.min(date)));
+            last_date = Some(last_date.map_or(date, |d| d.max(date)));
+        }
+
+        match (first_date, last_date) {
+            (Some(first), Some(last)) => info!(
+                "Loaded {} interactions from {:?} ({} to {})",
+                interactions.len(),
+                self.dir,
+                first,
+                last
+            ),
+            _ => info!(
+                "Loaded {} interactions from {:?}",
+                interactions.len(),
+                self.dir
+            ),
+        }
+
+        let mut matrix = self.aggregate(interactions.iter());
+        self.add_catalog(&mut matrix);
+
+        Ok(matrix)
+    }
+
+    async fn get_watermark(&mut self) -> Result<Option<Watermark>, Box<dyn std::error::Error>> {
+        let interactions = read_records::<InteractionRecord>(&self.dir, "interactions")?;
+        if interactions.iter().any(|i| i.date.is_none()) {
+            return Ok(None);
+        }
+        Ok(interactions
+            .iter()
+            .map(|i| {
+                (
+                    i.date.as_deref().unwrap_or_default(),
+                    i.document.as_deref().unwrap_or_default(),
+                )
+            })
+            .max()
+            .map(|(date, document)| Watermark {
+                date: date.to_string(),
+                document: document.to_string(),
+                extracted_on: None,
+            }))
+    }
+
+    async fn fetch_matrix_delta(
+        &mut self,
+        since: Option<&Watermark>,
+        until: &Watermark,
+    ) -> Result<MatrixDelta, Box<dyn std::error::Error>> {
+        if since.is_none() {
+            self.load_catalog()?;
+        }
+        let interactions = read_records::<InteractionRecord>(&self.dir, "interactions")?;
+
+        let mut added = self.aggregate(
+            interactions
+                .iter()
+                .filter(|i| since.is_none_or(|since| i.is_after(since)))
+                .filter(|i| !i.is_after(until)),
+        );
+        if since.is_none() {
+            self.add_catalog(&mut added);
+        }
+
+        // Dumps have no cancellation status; cancelled sales are simply absent
+        Ok(MatrixDelta {
+            added,
+            cancelled: HashMap::new(),
+        })
+    }
+
+    async fn fetch_returns(
+        &mut self,
+        since: Option<&str>,
+    ) -> Result<HashMap<String, ClientProductMatrix>, Box<dyn std::error::Error>> {
+        if !self.subtract_returns || !has_records(&self.dir, "returns") {
+            return Ok(HashMap::new());
+        }
+        let returns = read_records::<InteractionRecord>(&self.dir, "returns")?;
+
+        let mut by_document: HashMap<String, Vec<&InteractionRecord>> = HashMap::new();
+        for record in &returns {
+            if since.is_some_and(|since| record.date.as_deref().is_some_and(|date| date < since)) {
+                continue;
+            }
+            let document = record
+                .document
+                .clone()
+                .unwrap_or_else(|| record.content_key());
+            by_document.entry(document).or_default().push(record);
+        }
+        Ok(by_document
+            .into_iter()
+            .map(|(document, records)| (document, self.aggregate(records.into_iter())))
+            .collect())
+    }
+
+    async fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    async fn get_clients(
+        &mut self,
+        search: String,
+        page: i64,
+    ) -> Result<ClientPage, Box<dyn std::error::Error>> {
+        let search = fold(&search);
+        let matches: Vec<&ClientRow> = self
+            .clients
+            .iter()
+            .filter(|client| !self.excluded_clients.contains(&client.id))
+            .filter(|client| fold(&client.name).contains(&search))
+            .collect();
+
+        let clients = paginate(&matches, page)
+            .iter()
+            .map(|&client| client.clone())
+            .collect();
+
+        Ok(ClientPage {
+            current_page: page,
+            total_pages: total_pages(matches.len()),
+            clients,
+        })
+    }
+
+    async fn get_products(
+        &mut self,
+        search: String,
+        page: i64,
+    ) -> Result<ProductPage, Box<dyn std::error::Error>> {
+        let search = fold(&search);
+        let matches: Vec<&ProductRow> = self
+            .products
+            .iter()
+            .filter(|product| fold(&product.description).contains(&search))
+            .collect();
+
+        let products = paginate(&matches, page)
+            .iter()
+            .map(|&product| product.clone())
+            .collect();
+
+        Ok(ProductPage {
+            current_page: page,
+            total_pages: total_pages(matches.len()),
+            products,
+        })
+    }
+
+    async fn get_client_by_id(
+        &mut self,
+        id: String,
+    ) -> Result<ClientRow, Box<dyn std::error::Error>> {
+        self.clients
+            .iter()
+            .find(|client| client.id == id)
+            .cloned()
+            .ok_or_else(|| DatabaseError::NotFound(format!("client {}", id)).into())
+    }
+
+    async fn get_product_by_id(
+        &mut self,
+        id: String,
+    ) -> Result<ProductRow, Box<dyn std::error::Error>> {
+        self.products
+            .iter()
+            .find(|product| product.id == id)
+            .cloned()
+            .ok_or_else(|| DatabaseError::NotFound(format!("product {}", id)).into())
+    }
+
+    async fn get_all_clients(&mut self) -> Result<Vec<ClientRow>, Box<dyn std::error::Error>> {
+        Ok(self
+            .clients
+            .iter()
+            .filter(|client| !self.excluded_clients.contains(&client.id))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_all_products(&mut self) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>> {
+        Ok(self.products.clone())
+    }
+
+    async fn get_products_by_ids(
+        &mut self,
+        ids: Vec<String>,
+    ) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>> {
+        let ids: HashSet<String> = ids.into_iter().collect();
+        Ok(self
+            .products
+            .iter()
+            .filter(|product| ids.contains(&product.id))
+            .cloned()
+            .collect())
+    }
+}
+
+pub(crate) fn paginate<'a, T>(rows: &'a [&'a T], page: i64) -> &'a [&'a T] {
+    let start = ((page.max(1) - 1) as usize * PAGE_SIZE).min(rows.len());
+    let end = (start + PAGE_SIZE).min(rows.len());
+    &rows[start..end]
+}
+
+pub(crate) fn total_pages(count: usize) -> i64 {
+    (count as i64 - 1) / PAGE_SIZE as i64 + 1
+}
+
+fn has_records(dir: &Path, name: &str) -> bool {
+    dir.join(format!("{}.parquet", name)).exists() || dir.join(format!("{}.csv", name)).exists()
+}
+
+/// Reads `<name>.parquet` if present, falling back to `<name>.csv`.
+fn read_records<T: DeserializeOwned>(
+    dir: &Path,
+    name: &str,
+) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    let parquet_path = dir.join(format!("{}.parquet", name));
+    if parquet_path.exists() {
+        return read_parquet(&parquet_path);
+    }
+    let csv_path = dir.join(format!("{}.csv", name));
+    if csv_path.exists() {
+        return read_csv(&csv_path);
+    }
+    Err(format!(
+        "Neither {}.parquet nor {}.csv found in {:?}",
+        name, name, dir
+    )
+    .into())
+}
+
+fn read_csv<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+    let records = reader.deserialize().collect::<Result<Vec<T>, _>>()?;
+    Ok(records)
+}
+
+fn read_parquet<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    let mut records = Vec::new();
+    for row in reader.get_row_iter(None)? {
+        // Ids are often stored as integers in customer dumps; read every number as
+        // text so ids keep their exact form and quantities go through number_or_string
+        let value = match row?.to_json_value() {
+            serde_json::Value::Object(fields) => fields
+                .into_iter()
+                .map(|(name, value)| match value {
+                    serde_json::Value::Number(n) => {
+                        (name, serde_json::Value::String(n.to_string()))
+                    }
+                    value => (name, value),
+                })
+                .collect(),
+            value => value,
+        };
+        records.push(serde_json::from_value(value)?);
+    }
+    Ok(records)
+}
+
+/// Quantities and prices may come as numbers or as decimals encoded as strings
+fn number_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(n) => n
+            .as_f64()
+            .ok_or_else(|| serde::de::Error::custom("invalid number")),
+        serde_json::Value::String(s) if s.trim().is_empty() => Ok(0.0),
+        serde_json::Value::String(s) => s.trim().parse::<f64>().map_err(serde::de::Error::custom),
+        serde_json::Value::Null => Ok(0.0),
+        other => Err(serde::de::Error::custom(format!(
+            "expected number or string, got {}",
+            other
+        ))),
+    }
+}
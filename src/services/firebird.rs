@@ -1,103 +1,94 @@
 use crate::models::db::{
-    ClientPage, ClientProductMatrix, ClientRow, DatabaseTrait, ProductPage, ProductRow,
+    required, ClientPage, ClientProductMatrix, ClientRow, DatabaseError, DatabaseSettings,
+    DatabaseTrait, MatrixDelta, ProductPage, ProductRow, Watermark,
 };
+use crate::services::queries::{Dialect, QueryBuilder, MAX_IN_LIST};
 use async_trait::async_trait;
 use rsfbclient::{builder_pure_rust, Connection, FbError, Queryable};
 use rsfbclient_rust::RustFbClient;
 use std::collections::HashMap;
-use std::env;
 
 pub struct FirebirdDatabase {
     conn: Option<Connection<RustFbClient>>,
+    queries: QueryBuilder,
 }
 
 impl FirebirdDatabase {
-    pub fn new() -> Self {
-        dotenv::dotenv().ok();
-
-        let host = env::var("DB_HOST").expect("DB_HOST is not set");
-        let port = env::var("DB_PORT")
-            .expect("DB_PORT is not set")
-            .parse::<u16>()
-            .unwrap();
-        let username = env::var("DB_USERNAME").expect("DB_USERNAME is not set");
-        let password = env::var("DB_PASSWORD").expect("DB_PASSWORD is not set");
-        let database = env::var("DB_NAME").expect("DB_NAME is not set");
+    pub fn new(settings: &DatabaseSettings) -> Result<Self, DatabaseError> {
+        let connection_error = |e: String| DatabaseError::ConnectionError(e);
+        let host = required(&settings.host, "host").map_err(connection_error)?;
+        let port = *required(&settings.port, "port").map_err(connection_error)?;
+        let username = required(&settings.username, "username").map_err(connection_error)?;
+        let password = required(&settings.password, "password").map_err(connection_error)?;
+        let database = required(&settings.name, "name").map_err(connection_error)?;
 
         // Use pure rust builder
         let conn = builder_pure_rust()
-            .host(&host)
+            .host(host)
             .port(port)
-            .user(&username)
-            .pass(&password)
-            .db_name(&database)
+            .user(username)
+            .pass(password)
+            .db_name(database)
             .connect()
-            .unwrap();
+            .map_err(|e| {
+                DatabaseError::ConnectionError(format!("Failed to connect to Firebird: {}", e))
+            })?;
 
-        FirebirdDatabase { conn: Some(conn) }
+        Ok(FirebirdDatabase {
+            conn: Some(conn),
+            queries: QueryBuilder::new(settings.schema.clone(), Dialect::Firebird),
+        })
     }
 }
 
-#[async_trait]
-impl DatabaseTrait for FirebirdDatabase {
-    async fn build_client_product_matrix(
+impl FirebirdDatabase {
+    fn query_matrix(
         &mut self,
+        sql: &str,
     ) -> Result<ClientProductMatrix, Box<dyn std::error::Error>> {
-        let table_inve = env::var("TABLE_INVE").expect("TABLE_INVE is not set");
-        let table_fact = env::var("TABLE_FACT").expect("TABLE_FACT is not set");
-        let table_par_fact = env::var("TABLE_PAR_FACT").expect("TABLE_PAR_FACT is not set");
-        let table_client = env::var("TABLE_CLIENT").expect("TABLE_CLIENT is not set");
-
-        let excluded_clients: Vec<String> = env::var("EXCLUDED_CLIENTS")
-            .unwrap_or_default()
-            .split(',')
-            .map(|s| format!("'{}'", s.trim()))
-            .collect();
-
-        let excluded_clients_clause = if !excluded_clients.is_empty() {
-            format!("AND F.CVE_CLPV NOT IN ({})", excluded_clients.join(", "))
-        } else {
-            String::new()
-        };
-
-        let sql = format!(
-                    "SELECT F.CVE_CLPV AS CLIENT_ID, PF.CVE_ART AS PRODUCT_ID, SUM(PF.CANT) AS TOTAL_QUANTITY
-                     FROM {} AS PF
-                     INNER JOIN {} AS I ON PF.CVE_ART = I.CVE_ART
-                     INNER JOIN {} AS C INNER JOIN {} AS F ON C.CLAVE = F.CVE_CLPV
-                     ON PF.CVE_DOC = F.CVE_DOC WHERE F.STATUS <> 'C'
-                     AND C.NOMBRE NOT LIKE '%PUBLICO EN GENERAL%' {}
-                     AND I.STATUS = 'A'
-                     GROUP BY F.CVE_CLPV, PF.CVE_ART;",
-                    table_par_fact, table_inve, table_client, table_fact, excluded_clients_clause
-                );
-
-        let query_clients = format!(
-            "SELECT CLAVE AS CLIENT_ID
-                     FROM {};",
-            table_client
-        );
-
-        let query_products = format!(
-            "SELECT CVE_ART AS PRODUCT_ID
-                     FROM {} as I
-                     AND I.STATUS = 'A';",
-            table_inve
-        );
-
-        let mut matrix: ClientProductMatrix = {
-            let mut matrix = HashMap::new();
-            let rows = self.conn.as_mut().unwrap().query_iter(&sql, ())?;
+        let mut matrix = HashMap::new();
+        let rows = self.conn.as_mut().unwrap().query_iter(sql, ())?;
 
-            for row in rows {
-                let (client_id, product_id, total_quantity): (String, String, f64) = row?;
-                matrix
-                    .entry(client_id)
-                    .or_insert_with(HashMap::new)
-                    .insert(product_id, total_quantity);
-            }
+        for row in rows {
+            let (client_id, product_id, total_quantity): (String, String, f64) = row?;
             matrix
-        };
+                .entry(client_id)
+                .or_insert_with(HashMap::new)
+                .insert(product_id, total_quantity);
+        }
+        Ok(matrix)
+    }
+
+    /// Runs a query returning DOCUMENT, CLIENT_ID, PRODUCT_ID and TOTAL_QUANTITY rows
+    /// and groups the interactions by document
+    fn query_documents(
+        &mut self,
+        sql: &str,
+    ) -> Result<HashMap<String, ClientProductMatrix>, Box<dyn std::error::Error>> {
+        let mut documents: HashMap<String, ClientProductMatrix> = HashMap::new();
+        let rows = self.conn.as_mut().unwrap().query_iter(sql, ())?;
+
+        for row in rows {
+            let (document, client_id, product_id, total_quantity): (String, String, String, f64) =
+                row?;
+            documents
+                .entry(document)
+                .or_default()
+                .entry(client_id)
+                .or_default()
+                .insert(product_id, total_quantity);
+        }
+        Ok(documents)
+    }
+
+    /// Adds every client and active product to the matrix, so they are indexed by the
+    /// model even without interactions
+    fn add_catalog(
+        &mut self,
+        matrix: &mut ClientProductMatrix,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let query_clients = self.queries.all_clients();
+        let query_products = self.queries.active_products();
         {
             let rows = self.conn.as_mut().unwrap().query_iter(&query_clients, ())?;
             for row in rows {
@@ -118,10 +109,78 @@ impl DatabaseTrait for FirebirdDatabase {
                 }
             }
         }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DatabaseTrait for FirebirdDatabase {
+    async fn build_client_product_matrix(
+        &mut self,
+    ) -> Result<ClientProductMatrix, Box<dyn std::error::Error>> {
+        let sql = self.queries.interactions();
+
+        let mut matrix = self.query_matrix(&sql)?;
+        self.add_catalog(&mut matrix)?;
 
         Ok(matrix)
     }
 
+    async fn get_watermark(&mut self) -> Result<Option<Watermark>, Box<dyn std::error::Error>> {
+        let query = match self.queries.watermark() {
+            Some(query) => query,
+            None => return Ok(None),
+        };
+        let row: Option<(String, String)> = self.conn.as_mut().unwrap().query_first(&query, ())?;
+        Ok(row.map(|(date, document)| Watermark {
+            date,
+            document,
+            extracted_on: None,
+        }))
+    }
+
+    async fn fetch_matrix_delta(
+        &mut self,
+        since: Option<&Watermark>,
+        until: &Watermark,
+    ) -> Result<MatrixDelta, Box<dyn std::error::Error>> {
+        let sql = self
+            .queries
+            .interactions_between(since, until)
+            .ok_or("Incremental extraction requires an invoice date column")?;
+
+        let mut added = self.query_matrix(&sql)?;
+        if since.is_none() {
+            self.add_catalog(&mut added)?;
+        }
+
+        let query_cancelled = match since {
+            Some(since) => self
+                .queries
+                .cancelled_interactions(since, since.extracted_on.as_deref()),
+            None => self.queries.cancelled_interactions(until, None),
+        };
+        let cancelled = match query_cancelled {
+            Some(query_cancelled) => self.query_documents(&query_cancelled)?,
+            None => HashMap::new(),
+        };
+
+        Ok(MatrixDelta { added, cancelled })
+    }
+
+    async fn fetch_returns(
+        &mut self,
+        since: Option<&str>,
+    ) -> Result<HashMap<String, ClientProductMatrix>, Box<dyn std::error::Error>> {
+        let mut returns = HashMap::new();
+        for (table, sql) in self.queries.returned_interactions(since) {
+            for (document, interactions) in self.query_documents(&sql)? {
+                returns.insert(format!("{}:{}", table, document), interactions);
+            }
+        }
+        Ok(returns)
+    }
+
     async fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(conn) = self.conn.take() {
             conn.close()?;
@@ -129,44 +188,19 @@ impl DatabaseTrait for FirebirdDatabase {
         Ok(())
     }
 
+    async fn ping(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.as_mut().ok_or("Connection closed")?;
+        let _: Option<(i32,)> = conn.query_first("SELECT 1 FROM RDB$DATABASE", ())?;
+        Ok(())
+    }
+
     async fn get_clients(
         &mut self,
         search: String,
         page: i64,
     ) -> Result<ClientPage, Box<dyn std::error::Error>> {
-        let table_client = env::var("TABLE_CLIENT").expect("TABLE_CLIENT is not set");
-        let excluded_clients: Vec<String> = env::var("EXCLUDED_CLIENTS")
-            .unwrap_or_default()
-            .split(',')
-            .map(|s| format!("'{}'", s.trim()))
-            .collect();
-
-        let excluded_clients_clause = if !excluded_clients.is_empty() {
-            format!("AND CLAVE NOT IN ({})", excluded_clients.join(", "))
-        } else {
-            String::new()
-        };
-
-        let start = (page - 1) * 10 + 1;
-        let end = page * 10;
-
-        let query1 = format!(
-            "SELECT CLAVE as id, NOMBRE as name, EMAILPRED as email
-            FROM {}
-            WHERE NOMBRE LIKE '%{}%'
-            AND NOMBRE NOT LIKE '%PUBLICO EN GENERAL%' {}
-            ORDER BY id
-            ROWS {} TO {};",
-            table_client, search, excluded_clients_clause, start, end,
-        );
-
-        let query2 = format!(
-            "SELECT (COUNT(*)-1)/10 +1 as total_pages
-             FROM {}
-             WHERE NOMBRE LIKE '%{}%'
-             AND NOMBRE NOT LIKE '%PUBLICO EN GENERAL%' {}",
-            table_client, search, excluded_clients_clause,
-        );
+        let query1 = self.queries.clients_page(&search, page);
+        let query2 = self.queries.clients_total_pages(&search);
 
         let mut clients = Vec::new();
         let rows = self.conn.as_mut().unwrap().query_iter(&query1, ())?;
@@ -199,27 +233,8 @@ impl DatabaseTrait for FirebirdDatabase {
         search: String,
         page: i64,
     ) -> Result<ProductPage, Box<dyn std::error::Error>> {
-        let table_inve = env::var("TABLE_INVE").expect("TABLE_INVE is not set");
-
-        let query1 = format!(
-            "SELECT CVE_ART as id, DESCR as description, ULT_COSTO as price
-            FROM {} as I
-            WHERE DESCR LIKE '%{}%'
-            AND I.STATUS = 'A'
-            ORDER BY id
-            ROWS {} TO {};",
-            table_inve,
-            search,
-            (page - 1) * 10 + 1,
-            page * 10,
-        );
-        let query2 = format!(
-            "SELECT (COUNT(*)-1)/10+1 as total_pages
-             FROM {} as I
-             WHERE DESCR LIKE '%{}%'
-             AND I.STATUS = 'A'",
-            table_inve, search,
-        );
+        let query1 = self.queries.products_page(&search, page);
+        let query2 = self.queries.products_total_pages(&search);
 
         let mut products = Vec::new();
         let rows = self.conn.as_mut().unwrap().query_iter(&query1, ())?;
@@ -249,15 +264,10 @@ impl DatabaseTrait for FirebirdDatabase {
         &mut self,
         id: String,
     ) -> Result<ClientRow, Box<dyn std::error::Error>> {
-        let table_client = env::var("TABLE_CLIENT").expect("TABLE_CLIENT is not set");
-        let query = format!(
-            "SELECT CLAVE as id, NOMBRE as name, EMAILPRED as email
-            FROM {}
-            WHERE CLAVE = '{}';",
-            table_client, id
-        );
+        let query = self.queries.client_by_id(&id);
         let row = self.conn.as_mut().unwrap().query_first(&query, ())?;
-        let (id, name, email): (String, String, Option<String>) = row.unwrap();
+        let (id, name, email): (String, String, Option<String>) =
+            row.ok_or_else(|| DatabaseError::NotFound(format!("client {}", id)))?;
         Ok(ClientRow {
             id,
             name,
@@ -269,19 +279,62 @@ impl DatabaseTrait for FirebirdDatabase {
         &mut self,
         id: String,
     ) -> Result<ProductRow, Box<dyn std::error::Error>> {
-        let table_inve = env::var("TABLE_INVE").expect("TABLE_INVE is not set");
-        let query = format!(
-            "SELECT CVE_ART as id, DESCR as description, ULT_COSTO as price
-            FROM {}
-            WHERE CVE_ART = '{}';",
-            table_inve, id
-        );
+        let query = self.queries.product_by_id(&id);
         let row = self.conn.as_mut().unwrap().query_first(&query, ())?;
-        let (id, description, price): (String, String, f64) = row.unwrap();
+        let (id, description, price): (String, String, f64) =
+            row.ok_or_else(|| DatabaseError::NotFound(format!("product {}", id)))?;
         Ok(ProductRow {
             id,
             description,
             price,
         })
     }
+
+    async fn get_all_clients(&mut self) -> Result<Vec<ClientRow>, Box<dyn std::error::Error>> {
+        let query = self.queries.client_catalog();
+        let mut clients = Vec::new();
+        for row in self.conn.as_mut().unwrap().query_iter(&query, ())? {
+            let (id, name, email): (String, String, Option<String>) = row?;
+            clients.push(ClientRow {
+                id,
+                name,
+                email: email.unwrap_or("unknown_email".to_string()),
+            });
+        }
+        Ok(clients)
+    }
+
+    async fn get_all_products(&mut self) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>> {
+        let query = self.queries.product_catalog();
+        let mut products = Vec::new();
+        for row in self.conn.as_mut().unwrap().query_iter(&query, ())? {
+            let (id, description, price): (String, String, f64) = row?;
+            products.push(ProductRow {
+                id,
+                description,
+                price,
+            });
+        }
+        Ok(products)
+    }
+
+    async fn get_products_by_ids(
+        &mut self,
+        ids: Vec<String>,
+    ) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>> {
+        let conn = self.conn.as_mut().unwrap();
+        let mut products = Vec::with_capacity(ids.len());
+        for chunk in ids.chunks(MAX_IN_LIST) {
+            let query = self.queries.products_by_ids(chunk);
+            for row in conn.query_iter(&query, ())? {
+                let (id, description, price): (String, String, f64) = row?;
+                products.push(ProductRow {
+                    id,
+                    description,
+                    price,
+                });
+            }
+        }
+        Ok(products)
+    }
 }
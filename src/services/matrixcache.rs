@@ -0,0 +1,286 @@
+use crate::models::db::{
+    subtract_interactions, ClientProductMatrix, Database, DatabaseError, MatrixDelta, Watermark,
+};
+use crate::services::modelfile::write_atomically;
+use chrono::{DateTime, Duration, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Interaction matrix kept on disk between training runs, so each run only extracts
+/// the invoices posted or cancelled and the goods returned since the previous one
+#[derive(Serialize, Deserialize)]
+struct MatrixCache {
+    matrix: ClientProductMatrix,
+    watermark: Watermark,
+    /// When the matrix was last extracted from the whole invoice history
+    rebuilt_at: DateTime<Utc>,
+    /// Cancelled documents already subtracted from the matrix
+    cancelled_documents: HashSet<String>,
+    /// Return documents and credit notes already subtracted from the matrix
+    #[serde(default)]
+    returned_documents: HashSet<String>,
+}
+
+/// Returns the up to date client-product matrix, merging the invoices posted or
+/// cancelled since the cached extraction in `cache_file`. The whole history is
+/// extracted again when there is no cache or it is older than `rebuild_days`, and
+/// every time for backends that can't extract incrementally.
+pub async fn load_matrix(
+    db: &Database,
+    cache_file: &str,
+    rebuild_days: i64,
+) -> Result<ClientProductMatrix, DatabaseError> {
+    let until = match db.get_watermark().await? {
+        Some(watermark) => Watermark {
+            extracted_on: Some(Local::now().format("%Y-%m-%d").to_string()),
+            ..watermark
+        },
+        None => {
+            info!("Incremental extraction not available, building the whole matrix");
+            return db.build_matrix().await;
+        }
+    };
+
+    let cache = match read_cache(cache_file) {
+        Ok(cache) if Utc::now() - cache.rebuilt_at < Duration::days(rebuild_days) => Some(cache),
+        Ok(_) => {
+            info!(
+                "Matrix cache is older than {} days, rebuilding",
+                rebuild_days
+            );
+            None
+        }
+        Err(e) => {
+            info!(
+                "No usable matrix cache at {} ({}), rebuilding",
+                cache_file, e
+            );
+            None
+        }
+    };
+
+    let cache = match cache {
+        Some(mut cache) => {
+            let delta = db
+                .fetch_matrix_delta(Some(&cache.watermark), &until)
+                .await?;
+            let (added, cancelled) = apply_delta(&mut cache, delta);
+            info!(
+                "Merged {} new and {} cancelled interactions since {} {}",
+                added, cancelled, cache.watermark.date, cache.watermark.document
+            );
+            let returns = db
+                .fetch_returns(cache.watermark.extracted_on.as_deref())
+                .await?;
+            cache.watermark = until;
+            apply_returns(&mut cache, returns);
+            cache
+        }
+        None => {
+            let delta = db.fetch_matrix_delta(None, &until).await?;
+            // Cancelled invoices are already left out of the matrix; remember them so
+            // the next incremental run doesn't subtract them a second time
+            let mut cache = MatrixCache {
+                matrix: delta.added,
+                watermark: until,
+                rebuilt_at: Utc::now(),
+                cancelled_documents: delta.cancelled.into_keys().collect(),
+                returned_documents: HashSet::new(),
+            };
+            let returns = db.fetch_returns(None).await?;
+            apply_returns(&mut cache, returns);
+            cache
+        }
+    };
+
+    if let Err(e) = write_cache(cache_file, &cache) {
+        warn!("Failed to save matrix cache to {}: {}", cache_file, e);
+    }
+    Ok(cache.matrix)
+}
+
+/// Adds the new interactions and subtracts the cancelled ones, returning how many of
+/// each were merged
+fn apply_delta(cache: &mut MatrixCache, delta: MatrixDelta) -> (usize, usize) {
+    let mut added = 0;
+    for (client_id, products) in delta.added {
+        let client_products = cache.matrix.entry(client_id).or_default();
+        for (product_id, quantity) in products {
+            *client_products.entry(product_id).or_insert(0.0) += quantity;
+            added += 1;
+        }
+    }
+
+    let mut cancelled = 0;
+    for (document, interactions) in delta.cancelled {
+        if cache.cancelled_documents.insert(document) {
+            cancelled += subtract_interactions(&mut cache.matrix, interactions);
+        }
+    }
+    (added, cancelled)
+}
+
+/// Subtracts the returns not subtracted yet and logs how many interactions changed
+fn apply_returns(cache: &mut MatrixCache, returns: HashMap<String, ClientProductMatrix>) {
+    if returns.is_empty() {
+        return;
+    }
+    let mut documents = 0;
+    let mut adjusted = 0;
+    for (document, interactions) in returns {
+        if cache.returned_documents.insert(document) {
+            documents += 1;
+            adjusted += subtract_interactions(&mut cache.matrix, interactions);
+        }
+    }
+    info!(
+        "Subtracted {} return documents from {} interactions",
+        documents, adjusted
+    );
+}
+
+/// Reads a matrix snapshot: a matrix cache written by `load_matrix` or a plain
+/// client → product → quantity JSON file
+pub fn read_matrix_file(
+    file_path: &str,
+) -> Result<ClientProductMatrix, Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Snapshot {
+        Cache(MatrixCache),
+        Matrix(ClientProductMatrix),
+    }
+    let file = File::open(file_path)?;
+    Ok(match serde_json::from_reader(file)? {
+        Snapshot::Cache(cache) => cache.matrix,
+        Snapshot::Matrix(matrix) => matrix,
+    })
+}
+
+fn read_cache(file_path: &str) -> Result<MatrixCache, Box<dyn std::error::Error>> {
+    let file = File::open(file_path)?;
+    let cache = serde_json::from_reader(file)?;
+    Ok(cache)
+}
+
+/// Replaces the cache atomically: a truncated cache would force a full extraction
+fn write_cache(
+    file_path: &str,
+    cache: &MatrixCache,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    write_atomically(Path::new(file_path), |writer| {
+        serde_json::to_writer(writer, cache)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interactions(rows: &[(&str, &str, f64)]) -> ClientProductMatrix {
+        let mut matrix = ClientProductMatrix::new();
+        for &(client, product, quantity) in rows {
+            matrix
+                .entry(client.to_string())
+                .or_default()
+                .insert(product.to_string(), quantity);
+        }
+        matrix
+    }
+
+    fn cache(rows: &[(&str, &str, f64)]) -> MatrixCache {
+        MatrixCache {
+            matrix: interactions(rows),
+            watermark: Watermark {
+                date: "2024-01-01".to_string(),
+                document: "F-0".to_string(),
+                extracted_on: None,
+            },
+            rebuilt_at: Utc::now(),
+            cancelled_documents: HashSet::new(),
+            returned_documents: HashSet::new(),
+        }
+    }
+
+    fn cancellation(document: &str, rows: &[(&str, &str, f64)]) -> MatrixDelta {
+        MatrixDelta {
+            added: ClientProductMatrix::new(),
+            cancelled: HashMap::from([(document.to_string(), interactions(rows))]),
+        }
+    }
+
+    fn quantity(cache: &MatrixCache, client: &str, product: &str) -> f64 {
+        cache.matrix[client][product]
+    }
+
+    #[test]
+    fn new_interactions_are_added() {
+        let mut cache = cache(&[("c1", "p1", 5.0)]);
+        let delta = MatrixDelta {
+            added: interactions(&[("c1", "p1", 1.0), ("c2", "p2", 3.0)]),
+            cancelled: HashMap::new(),
+        };
+        assert_eq!(apply_delta(&mut cache, delta), (2, 0));
+        assert_eq!(quantity(&cache, "c1", "p1"), 6.0);
+        assert_eq!(quantity(&cache, "c2", "p2"), 3.0);
+    }
+
+    #[test]
+    fn cancelled_document_is_subtracted_once() {
+        let mut cache = cache(&[("c1", "p1", 5.0)]);
+        let cancelled = [("c1", "p1", 2.0)];
+        assert_eq!(
+            apply_delta(&mut cache, cancellation("F-1", &cancelled)),
+            (0, 1)
+        );
+        assert_eq!(
+            apply_delta(&mut cache, cancellation("F-1", &cancelled)),
+            (0, 0)
+        );
+        assert_eq!(quantity(&cache, "c1", "p1"), 3.0);
+    }
+
+    #[test]
+    fn documents_cancelled_before_a_rebuild_are_not_subtracted() {
+        let mut cache = cache(&[("c1", "p1", 5.0)]);
+        cache.cancelled_documents.insert("F-1".to_string());
+        apply_delta(&mut cache, cancellation("F-1", &[("c1", "p1", 2.0)]));
+        assert_eq!(quantity(&cache, "c1", "p1"), 5.0);
+    }
+
+    #[test]
+    fn return_document_is_subtracted_once() {
+        let mut cache = cache(&[("c1", "p1", 5.0)]);
+        let returns = || HashMap::from([("D-1".to_string(), interactions(&[("c1", "p1", 1.0)]))]);
+        apply_returns(&mut cache, returns());
+        apply_returns(&mut cache, returns());
+        assert_eq!(quantity(&cache, "c1", "p1"), 4.0);
+        assert!(cache.returned_documents.contains("D-1"));
+    }
+
+    #[test]
+    fn quantities_never_go_below_zero() {
+        let mut cache = cache(&[("c1", "p1", 2.0), ("c1", "p2", 1.0)]);
+        apply_delta(&mut cache, cancellation("F-1", &[("c1", "p1", 5.0)]));
+        apply_returns(
+            &mut cache,
+            HashMap::from([("D-1".to_string(), interactions(&[("c1", "p2", 3.0)]))]),
+        );
+        assert_eq!(quantity(&cache, "c1", "p1"), 0.0);
+        assert_eq!(quantity(&cache, "c1", "p2"), 0.0);
+    }
+
+    #[test]
+    fn unknown_interactions_are_ignored() {
+        let mut cache = cache(&[("c1", "p1", 2.0)]);
+        apply_delta(
+            &mut cache,
+            cancellation("F-1", &[("c2", "p1", 1.0), ("c1", "p9", 1.0)]),
+        );
+        assert_eq!(cache.matrix, interactions(&[("c1", "p1", 2.0)]));
+    }
+}
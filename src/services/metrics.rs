@@ -0,0 +1,147 @@
+use crate::services::modelserver::ServedModel;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::time::Duration;
+use tracing::error;
+
+lazy_static::lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref HTTP_LATENCY: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency by route and method",
+        &["route", "method"]
+    )
+    .unwrap();
+    static ref RECOMMENDATION_CACHE: IntCounterVec = register_int_counter_vec!(
+        "recommendation_cache_requests_total",
+        "Recommendation cache lookups by tenant and result (hit or miss)",
+        &["tenant", "result"]
+    )
+    .unwrap();
+    static ref DB_LATENCY: HistogramVec = register_histogram_vec!(
+        "db_query_duration_seconds",
+        "Database call latency by method",
+        &["method"]
+    )
+    .unwrap();
+    static ref MODEL_LOAD: GaugeVec = register_gauge_vec!(
+        "model_load_seconds",
+        "Time taken to load the served model",
+        &["tenant"]
+    )
+    .unwrap();
+    static ref TRAINING_DURATION: HistogramVec = register_histogram_vec!(
+        "training_duration_seconds",
+        "Duration of training runs by tenant and outcome",
+        &["tenant", "state"],
+        vec![60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0, 86400.0]
+    )
+    .unwrap();
+    static ref MODEL_EPR: GaugeVec = register_gauge_vec!(
+        "model_epr",
+        "Expected percentile rank of the served model, lower is better",
+        &["tenant"]
+    )
+    .unwrap();
+    static ref MODEL_CLIENTS: IntGaugeVec = register_int_gauge_vec!(
+        "model_clients",
+        "Clients in the served model",
+        &["tenant"]
+    )
+    .unwrap();
+    static ref MODEL_PRODUCTS: IntGaugeVec = register_int_gauge_vec!(
+        "model_products",
+        "Products in the served model",
+        &["tenant"]
+    )
+    .unwrap();
+}
+
+/// Records a served request; `path` is reduced to its route so ids don't end up
+/// in the labels
+pub fn record_request(info: warp::log::Info) {
+    let route = route_label(info.path());
+    let method = info.method().as_str();
+    HTTP_REQUESTS
+        .with_label_values(&[&route, method, info.status().as_str()])
+        .inc();
+    HTTP_LATENCY
+        .with_label_values(&[&route, method])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+pub fn record_cache(tenant: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    RECOMMENDATION_CACHE
+        .with_label_values(&[tenant, result])
+        .inc();
+}
+
+/// Times a database call until the returned timer is dropped
+pub fn db_timer(method: &str) -> prometheus::HistogramTimer {
+    DB_LATENCY.with_label_values(&[method]).start_timer()
+}
+
+/// Updates the gauges of the model `tenant` now serves
+pub fn record_model(tenant: &str, model: &ServedModel, load_time: Duration) {
+    MODEL_LOAD
+        .with_label_values(&[tenant])
+        .set(load_time.as_secs_f64());
+    MODEL_EPR
+        .with_label_values(&[tenant])
+        .set(model.epr().unwrap_or(f64::NAN));
+    MODEL_CLIENTS
+        .with_label_values(&[tenant])
+        .set(model.num_clients() as i64);
+    MODEL_PRODUCTS
+        .with_label_values(&[tenant])
+        .set(model.num_products() as i64);
+}
+
+pub fn record_training(tenant: &str, state: &str, duration: Duration) {
+    TRAINING_DURATION
+        .with_label_values(&[tenant, state])
+        .observe(duration.as_secs_f64());
+}
+
+/// Every metric in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Route of a request path: the `/t/{tenant}` prefix is dropped and the path
+/// parameters replaced by their names
+pub fn route_label(path: &str) -> String {
+    let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.first() == Some(&"t") && segments.len() >= 2 {
+        segments.drain(..2);
+    }
+    match segments.as_slice() {
+        [] => "/".to_string(),
+        ["recommend", "batch"] => "/recommend/batch".to_string(),
+        ["recommend", _] => "/recommend/{client}".to_string(),
+        ["recommend", _, _] => "/recommend/{client}/{n}".to_string(),
+        ["client", _] => "/client/{id}".to_string(),
+        ["product", _] => "/product/{id}".to_string(),
+        ["models", version, "activate"] if version.parse::<u64>().is_ok() => {
+            "/models/{version}/activate".to_string()
+        }
+        ["metadata" | "clients" | "products" | "healthz" | "readyz" | "status" | "metrics"]
+        | ["models"]
+        | ["models", "rollback"]
+        | ["admin", "train"]
+        | ["admin", "train", "status" | "events" | "cancel"] => format!("/{}", segments.join("/")),
+        _ => "other".to_string(),
+    }
+}
@@ -1,6 +1,18 @@
 pub mod als;
+pub mod catalog;
 pub mod cronjobs;
+pub mod events;
+pub mod files;
 pub mod firebird;
+pub mod matrixcache;
+pub mod metrics;
+pub mod modelfile;
 pub mod modelserver;
 pub mod mssql;
+pub mod promotion;
+pub mod queries;
+pub mod registry;
+pub mod schedule;
+pub mod search;
+pub mod tenants;
 pub mod training;
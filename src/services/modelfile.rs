@@ -0,0 +1,583 @@
+use crate::services::training::{Hyperparameters, JSONData};
+use chrono::{DateTime, Utc};
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const MAGIC: &[u8; 8] = b"PMMODEL\0";
+pub const FORMAT_VERSION: u32 = 2;
+/// Same layout as version 2 without the trailing checksum
+const FORMAT_VERSION_UNCHECKED: u32 = 1;
+
+/// Precision of the factor blocks; `f32` halves the file size and is plenty for
+/// ranking products
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    F32,
+    F64,
+}
+
+impl Precision {
+    fn size(self) -> u32 {
+        match self {
+            Precision::F32 => 4,
+            Precision::F64 => 8,
+        }
+    }
+}
+
+/// How model files are written and read, set once from the configuration at startup
+#[derive(Debug, Clone, Copy)]
+pub struct ModelFileOptions {
+    pub precision: Precision,
+}
+
+static OPTIONS: OnceLock<ModelFileOptions> = OnceLock::new();
+
+/// Sets the options of every model file written or read afterwards
+pub fn configure(options: ModelFileOptions) {
+    let _ = OPTIONS.set(options);
+}
+
+fn options() -> ModelFileOptions {
+    *OPTIONS.get_or_init(|| ModelFileOptions {
+        precision: Precision::F32,
+    })
+}
+
+/// Description of a trained model, stored in the model file header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    /// Registry version, assigned when the model is published
+    #[serde(default)]
+    pub version: Option<u64>,
+    pub hyperparameters: Hyperparameters,
+    pub epr: Option<f64>,
+    pub trained_at: Option<DateTime<Utc>>,
+    pub num_clients: usize,
+    pub num_products: usize,
+    /// Client-product pairs with purchases in the training data
+    #[serde(default)]
+    pub num_interactions: usize,
+}
+
+impl ModelMetadata {
+    pub fn new(data: &JSONData, epr: Option<f64>) -> Self {
+        ModelMetadata {
+            version: None,
+            hyperparameters: data.hyperparameters.clone(),
+            epr,
+            trained_at: Some(Utc::now()),
+            num_clients: data.client_index.len(),
+            num_products: data.product_index.len(),
+            num_interactions: data
+                .matrix
+                .values()
+                .flat_map(|products| products.values())
+                .filter(|&&quantity| quantity > 0.0)
+                .count(),
+        }
+    }
+}
+
+/// Saves a trained model. Files ending in `.json` keep the legacy `JSONData` layout,
+/// without version or checksum, so they are only for exports and can't be published;
+/// anything else uses the binary format:
+///
+/// ```text
+/// magic "PMMODEL\0" | version u32 | precision u32 (bytes per value)
+/// num_clients u64 | num_products u64 | num_factors u64 | metadata_len u64
+/// metadata (JSON)
+/// client ids | product ids             (u32 length + UTF-8, in index order)
+/// num_interactions u64 | (client u32, product u32, quantity f64) * num_interactions
+/// client factors | product factors     (row-major blocks of f32 or f64)
+/// checksum u32                         (CRC32 of everything before it)
+/// ```
+///
+/// All numbers are little endian. The file is replaced atomically, see
+/// `write_atomically`.
+pub fn save_model(data: &JSONData, metadata: &ModelMetadata, file_path: &str) -> Result<(), Error> {
+    write_atomically(Path::new(file_path), |writer| {
+        if is_json(file_path) {
+            serde_json::to_writer(writer, data)?;
+        } else {
+            let mut checksummed = ChecksumWriter {
+                inner: &mut *writer,
+                hasher: Hasher::new(),
+            };
+            write_binary(&mut checksummed, data, metadata, options().precision)?;
+            let checksum = checksummed.hasher.finalize();
+            writer.write_all(&checksum.to_le_bytes())?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes `path` through a temporary file next to it that is synced and renamed over
+/// it, so readers only ever see the previous or the complete new contents
+pub fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let tmp_path = temp_path(path);
+    let result = File::create(&tmp_path)
+        .map_err(Error::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            Ok(fs::rename(&tmp_path, path)?)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    // Persist the rename itself; not every platform can open a directory for syncing
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Temporary file `path` is written to before being renamed over it
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Loads a model saved in either format, telling them apart by the magic bytes, and
+/// checks it is complete and consistent: binary files must have a supported format
+/// version and a matching checksum. Version 1 files, written before checksums, are
+/// still read; `convert-model` upgrades them.
+pub fn load_model(file_path: &str) -> Result<(JSONData, ModelMetadata), Error> {
+    let bytes = fs::read(file_path)?;
+    if bytes.starts_with(MAGIC) {
+        read_binary(&bytes)
+    } else {
+        let data: JSONData = serde_json::from_slice(&bytes)?;
+        validate(&data)?;
+        let metadata = ModelMetadata {
+            trained_at: None,
+            ..ModelMetadata::new(&data, None)
+        };
+        Ok((data, metadata))
+    }
+}
+
+/// Rewrites a model file in the format given by the extension of `output`
+pub fn convert_model(input: &str, output: &str) -> Result<ModelMetadata, Error> {
+    let (data, metadata) = load_model(input)?;
+    save_model(&data, &metadata, output)?;
+    Ok(metadata)
+}
+
+/// Whether `file_path` is written in the legacy JSON layout, which has no format
+/// version or checksum
+pub fn is_json(file_path: &str) -> bool {
+    Path::new(file_path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+fn write_binary(
+    writer: &mut impl Write,
+    data: &JSONData,
+    metadata: &ModelMetadata,
+    precision: Precision,
+) -> Result<(), Error> {
+    validate(data)?;
+    let client_ids = ids_by_index(&data.client_index)?;
+    let product_ids = ids_by_index(&data.product_index)?;
+    let num_factors = data.hyperparameters.num_factors;
+    let metadata = serde_json::to_vec(metadata)?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&precision.size().to_le_bytes())?;
+    for value in [
+        client_ids.len(),
+        product_ids.len(),
+        num_factors,
+        metadata.len(),
+    ] {
+        writer.write_all(&(value as u64).to_le_bytes())?;
+    }
+    writer.write_all(&metadata)?;
+
+    for id in client_ids.iter().chain(product_ids.iter()) {
+        writer.write_all(&(id.len() as u32).to_le_bytes())?;
+        writer.write_all(id.as_bytes())?;
+    }
+
+    let interactions: Vec<(u32, u32, f64)> = data
+        .matrix
+        .iter()
+        .filter_map(|(client_id, products)| Some((data.client_index.get(client_id)?, products)))
+        .flat_map(|(&client_idx, products)| {
+            products.iter().filter_map(move |(product_id, &quantity)| {
+                let product_idx = *data.product_index.get(product_id)?;
+                Some((client_idx as u32, product_idx as u32, quantity))
+            })
+        })
+        .collect();
+    writer.write_all(&(interactions.len() as u64).to_le_bytes())?;
+    for (client_idx, product_idx, quantity) in interactions {
+        writer.write_all(&client_idx.to_le_bytes())?;
+        writer.write_all(&product_idx.to_le_bytes())?;
+        writer.write_all(&quantity.to_le_bytes())?;
+    }
+
+    for factors in [&data.client_factors, &data.product_factors] {
+        for value in factors.iter().flatten() {
+            match precision {
+                Precision::F32 => writer.write_all(&(*value as f32).to_le_bytes())?,
+                Precision::F64 => writer.write_all(&value.to_le_bytes())?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_binary(bytes: &[u8]) -> Result<(JSONData, ModelMetadata), Error> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a model file".into());
+    }
+    let body = match reader.u32()? {
+        FORMAT_VERSION => {
+            let (body, checksum) = bytes
+                .split_last_chunk::<4>()
+                .ok_or("Model file is truncated")?;
+            if crc32fast::hash(body) != u32::from_le_bytes(*checksum) {
+                return Err("Model file checksum mismatch".into());
+            }
+            body
+        }
+        FORMAT_VERSION_UNCHECKED => bytes,
+        version => {
+            return Err(format!(
+                "Unsupported model format version {} (expected {})",
+                version, FORMAT_VERSION
+            )
+            .into())
+        }
+    };
+    let mut reader = Reader {
+        bytes: body,
+        pos: reader.pos,
+    };
+    let precision = match reader.u32()? {
+        4 => Precision::F32,
+        8 => Precision::F64,
+        size => return Err(format!("Unsupported factor size {}", size).into()),
+    };
+    let num_clients = reader.u64()? as usize;
+    let num_products = reader.u64()? as usize;
+    let num_factors = reader.u64()? as usize;
+    let metadata_len = reader.u64()? as usize;
+    let metadata: ModelMetadata = serde_json::from_slice(reader.take(metadata_len)?)?;
+    if metadata.hyperparameters.num_factors != num_factors {
+        return Err("Number of factors doesn't match the model metadata".into());
+    }
+
+    let client_ids = reader.strings(num_clients)?;
+    let product_ids = reader.strings(num_products)?;
+
+    // Clients without interactions keep their (empty) row, as in the trained matrix
+    let mut matrix: HashMap<String, HashMap<String, f64>> = client_ids
+        .iter()
+        .map(|client_id| (client_id.clone(), HashMap::new()))
+        .collect();
+    let num_interactions = reader.u64()? as usize;
+    for _ in 0..num_interactions {
+        let client_idx = reader.u32()? as usize;
+        let product_idx = reader.u32()? as usize;
+        let quantity = reader.f64()?;
+        let (Some(client_id), Some(product_id)) =
+            (client_ids.get(client_idx), product_ids.get(product_idx))
+        else {
+            return Err("Interaction refers to an unknown client or product".into());
+        };
+        if let Some(products) = matrix.get_mut(client_id) {
+            products.insert(product_id.clone(), quantity);
+        }
+    }
+
+    let client_factors = reader.factors(num_clients, num_factors, precision)?;
+    let product_factors = reader.factors(num_products, num_factors, precision)?;
+
+    if reader.pos != body.len() {
+        return Err("Unexpected data after the factor blocks".into());
+    }
+
+    let data = JSONData {
+        hyperparameters: metadata.hyperparameters.clone(),
+        matrix,
+        product_factors,
+        client_factors,
+        client_index: index_of(client_ids),
+        product_index: index_of(product_ids),
+    };
+    Ok((data, metadata))
+}
+
+/// Checks the factor matrices match the indexes and the number of factors, so a
+/// corrupt model is rejected instead of breaking the server
+fn validate(data: &JSONData) -> Result<(), Error> {
+    let num_factors = data.hyperparameters.num_factors;
+    for (factors, index) in [
+        (&data.client_factors, &data.client_index),
+        (&data.product_factors, &data.product_index),
+    ] {
+        ids_by_index(index)?;
+        if factors.len() != index.len() || factors.iter().any(|row| row.len() != num_factors) {
+            return Err("Factor matrix doesn't match the index and number of factors".into());
+        }
+    }
+    Ok(())
+}
+
+/// Feeds everything written through it to a CRC32 hasher
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Ids ordered by their position in the factor matrices
+fn ids_by_index(index: &HashMap<String, usize>) -> Result<Vec<String>, Error> {
+    let mut ids = vec![None; index.len()];
+    for (id, &idx) in index {
+        match ids.get_mut(idx) {
+            Some(slot @ None) => *slot = Some(id.clone()),
+            _ => return Err(format!("Invalid index {} for '{}'", idx, id).into()),
+        }
+    }
+    Ok(ids.into_iter().flatten().collect())
+}
+
+fn index_of(ids: Vec<String>) -> HashMap<String, usize> {
+    ids.into_iter().enumerate().map(|(i, id)| (id, i)).collect()
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or("Model file is truncated")?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn strings(&mut self, count: usize) -> Result<Vec<String>, Error> {
+        (0..count)
+            .map(|_| {
+                let len = self.u32()? as usize;
+                Ok(std::str::from_utf8(self.take(len)?)?.to_string())
+            })
+            .collect()
+    }
+
+    fn factors(
+        &mut self,
+        rows: usize,
+        num_factors: usize,
+        precision: Precision,
+    ) -> Result<Vec<Vec<f64>>, Error> {
+        let size = precision.size() as usize;
+        let block = self.take(
+            rows.checked_mul(num_factors)
+                .and_then(|values| values.checked_mul(size))
+                .ok_or("Model file is truncated")?,
+        )?;
+        Ok(block
+            .chunks_exact(size * num_factors.max(1))
+            .take(rows)
+            .map(|row| {
+                row.chunks_exact(size)
+                    .map(|value| match precision {
+                        Precision::F32 => f32::from_le_bytes(value.try_into().unwrap()) as f64,
+                        Precision::F64 => f64::from_le_bytes(value.try_into().unwrap()),
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> (JSONData, ModelMetadata) {
+        let data = JSONData {
+            hyperparameters: Hyperparameters {
+                num_factors: 2,
+                regularization: 0.1,
+                confidence_multiplier: 40.0,
+            },
+            matrix: HashMap::from([
+                (
+                    "c1".to_string(),
+                    HashMap::from([("p1".to_string(), 2.0), ("p3".to_string(), 1.5)]),
+                ),
+                // Clients without purchases keep their row
+                ("c2".to_string(), HashMap::new()),
+            ]),
+            client_factors: vec![vec![0.1, 1.0 / 3.0], vec![-2.5, 1e-3]],
+            product_factors: vec![vec![0.7, 0.2], vec![1.0, -1.0], vec![0.0, 2.0 / 3.0]],
+            client_index: index_of(vec!["c1".to_string(), "c2".to_string()]),
+            product_index: index_of(vec!["p1".to_string(), "p2".to_string(), "p3".to_string()]),
+        };
+        let metadata = ModelMetadata::new(&data, Some(0.25));
+        (data, metadata)
+    }
+
+    /// A version 2 file, as written by `save_model`
+    fn encode(precision: Precision) -> Vec<u8> {
+        let (data, metadata) = model();
+        let mut writer = ChecksumWriter {
+            inner: Vec::new(),
+            hasher: Hasher::new(),
+        };
+        write_binary(&mut writer, &data, &metadata, precision).unwrap();
+        let checksum = writer.hasher.finalize();
+        let mut bytes = writer.inner;
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// A version 1 file: the same layout without the checksum
+    fn encode_v1() -> Vec<u8> {
+        let (data, metadata) = model();
+        let mut bytes = Vec::new();
+        write_binary(&mut bytes, &data, &metadata, Precision::F64).unwrap();
+        bytes[MAGIC.len()..MAGIC.len() + 4]
+            .copy_from_slice(&FORMAT_VERSION_UNCHECKED.to_le_bytes());
+        bytes
+    }
+
+    fn assert_same_model(read: &JSONData, metadata: &ModelMetadata, tolerance: f64) {
+        let (data, expected) = model();
+        assert_eq!(read.matrix, data.matrix);
+        assert_eq!(read.client_index, data.client_index);
+        assert_eq!(read.product_index, data.product_index);
+        assert_eq!(read.hyperparameters.num_factors, 2);
+        for (read, written) in [
+            (&read.client_factors, &data.client_factors),
+            (&read.product_factors, &data.product_factors),
+        ] {
+            assert_eq!(read.len(), written.len());
+            for (a, b) in read.iter().flatten().zip(written.iter().flatten()) {
+                assert!((a - b).abs() <= tolerance, "{} != {}", a, b);
+            }
+        }
+        assert_eq!(metadata.epr, expected.epr);
+        assert_eq!(metadata.num_interactions, 2);
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match read_binary(bytes) {
+            Ok(_) => panic!("the model file was accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip_f64() {
+        let (data, metadata) = read_binary(&encode(Precision::F64)).unwrap();
+        assert_same_model(&data, &metadata, 0.0);
+    }
+
+    #[test]
+    fn round_trip_f32() {
+        let bytes = encode(Precision::F32);
+        assert!(bytes.len() < encode(Precision::F64).len());
+        let (data, metadata) = read_binary(&bytes).unwrap();
+        assert_same_model(&data, &metadata, 1e-6);
+    }
+
+    #[test]
+    fn corrupted_byte_fails_the_checksum() {
+        let mut bytes = encode(Precision::F32);
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        assert!(error(&bytes).contains("checksum"));
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let bytes = encode(Precision::F32);
+        assert!(error(&bytes[..bytes.len() - 1]).contains("checksum"));
+        assert!(error(&bytes[..MAGIC.len() + 2]).contains("truncated"));
+    }
+
+    #[test]
+    fn version_1_file_is_still_read() {
+        let (data, metadata) = read_binary(&encode_v1()).unwrap();
+        assert_same_model(&data, &metadata, 0.0);
+    }
+
+    #[test]
+    fn version_1_file_is_checked_for_length() {
+        let mut bytes = encode_v1();
+        bytes.push(0);
+        assert!(error(&bytes).contains("Unexpected data"));
+        bytes.truncate(bytes.len() - 2);
+        assert!(error(&bytes).contains("truncated"));
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut bytes = encode(Precision::F32);
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(error(&bytes).contains("Unsupported model format version"));
+    }
+}
@@ -1,21 +1,31 @@
-use crate::models::db::{
-    ClientPage, ClientRow, Database, DatabaseError, DatabaseTrait, ProductPage, ProductRow,
+use crate::config::{CatalogConfig, TrainingConfig};
+use crate::error::AppError;
+use crate::models::db::{ClientPage, ClientRow, Database, DatabaseError, ProductPage, ProductRow};
+use crate::services::als::{RecommendOptions, ALS};
+use crate::services::catalog::{Catalog, CatalogStatus};
+use crate::services::events::TrainingEvents;
+use crate::services::matrixcache::load_matrix;
+use crate::services::metrics;
+use crate::services::modelfile::{convert_model, load_model, ModelMetadata};
+use crate::services::registry::{ModelRegistry, ModelVersion, ModelVersions};
+use crate::services::tenants::Tenant;
+use crate::services::training::{
+    find_best_als_model, HyperparameterGrid, TrainingRun, TrainingState, TrainingStatus,
 };
-use crate::services::als::ALS;
-use crate::services::firebird::FirebirdDatabase;
-use crate::services::mssql::SqlServerDatabase;
-use crate::services::training::find_best_als_model;
-use futures::future::join_all;
+use arc_swap::ArcSwapOption;
+use chrono::{DateTime, Utc};
+use moka::sync::Cache;
 use notify::{recommended_watcher, EventKind, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex as TokioMutex, Notify};
-use tokio::time::{sleep, Duration};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
 
 use super::training::JSONData;
+use tracing::{error, info, info_span, warn, Instrument, Span};
 
 #[derive(Serialize, Deserialize)]
 pub struct Recommendation {
@@ -23,125 +33,418 @@ pub struct Recommendation {
     pub products: Vec<ProductRow>,
 }
 
+/// Clients accepted in one batch of recommendations
+const MAX_BATCH_CLIENTS: usize = 500;
+
+/// Body of `POST /recommend/batch`
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    pub clients: Vec<String>,
+    #[serde(flatten)]
+    pub options: RecommendOptions,
+}
+
+#[derive(Serialize)]
+pub struct BatchRecommendation {
+    pub client_id: String,
+    pub products: Vec<ProductRow>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    /// In the order of the request, without the unknown clients
+    pub recommendations: Vec<BatchRecommendation>,
+    /// Clients the model doesn't know, e.g. without purchases
+    pub unknown_clients: Vec<String>,
+}
+
+/// Recommendations kept per model; the least useful ones are evicted beyond that
+const RECOMMENDATION_CACHE_SIZE: u64 = 10_000;
+
+/// Recommended product ids by client and count
+type RecommendationCache = Cache<(String, Option<usize>), Vec<String>>;
+
+/// What a training run trains
+enum TrainingPlan {
+    /// Full search over the grid
+    Search(HyperparameterGrid),
+    /// The served model's hyperparameters, starting from its factors; the model file
+    /// is only read once the run is started
+    Refit,
+}
+
+/// A loaded model with the metadata it was published with
+pub struct ServedModel {
+    pub als: ALS,
+    pub metadata: ModelMetadata,
+    /// Dropped with the model, so a new model never serves stale recommendations.
+    /// Concurrent, so requests don't wait on each other for it.
+    recommendations: RecommendationCache,
+}
+
+impl ServedModel {
+    pub fn new(als: ALS, metadata: ModelMetadata) -> Self {
+        ServedModel {
+            als,
+            metadata,
+            recommendations: Cache::new(RECOMMENDATION_CACHE_SIZE),
+        }
+    }
+
+    /// EPR of the model when it was published
+    pub fn epr(&self) -> Option<f64> {
+        self.metadata.epr
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.als
+            .client_index
+            .as_ref()
+            .map_or(0, |index| index.len())
+    }
+
+    pub fn num_products(&self) -> usize {
+        self.als
+            .product_index
+            .as_ref()
+            .map_or(0, |index| index.len())
+    }
+
+    /// Recommended product ids for `client_id`, from the cache when possible
+    fn recommend(&self, tenant: &str, client_id: &str, n: Option<usize>) -> Vec<String> {
+        let key = (client_id.to_string(), n);
+        if let Some(products) = self.recommendations.get(&key) {
+            metrics::record_cache(tenant, true);
+            return products;
+        }
+        metrics::record_cache(tenant, false);
+        let products = self.als.recommend(client_id, n);
+        self.recommendations.insert(key, products.clone());
+        products
+    }
+}
+
+/// Serves one tenant. Shared as immutable state between requests: the model is swapped
+/// atomically when a new one is published and database calls use a connection pool,
+/// so requests never wait on each other or on a reload.
 pub struct ModelServer {
-    model: Arc<TokioMutex<Option<ALS>>>,
+    tenant: String,
+    model: Arc<ArcSwapOption<ServedModel>>,
     hyperparameters_file: String,
-    notify: Option<Arc<Notify>>,
-    db: Option<Arc<TokioMutex<Database>>>,
+    registry: ModelRegistry,
+    notify: Arc<Notify>,
+    db: Database,
+    /// Clients and products served from memory, in front of `db`
+    catalog: Catalog,
+    /// Asks the catalog to reload, e.g. once a new model is served
+    catalog_reload: Arc<Notify>,
+    matrix_file: String,
+    settings: TrainingConfig,
+    /// Current or last training run
+    training: Mutex<Option<Arc<TrainingRun>>>,
+    /// Progress of every training run of the tenant
+    events: TrainingEvents,
 }
 
+pub type SharedModelServer = Arc<ModelServer>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MetadataModel {
+    /// Registry version of the served model
+    version: Option<u64>,
+    trained_at: Option<DateTime<Utc>>,
     num_factors: usize,
     regularization: f64,
     confidence_multiplier: f64,
     epr: f64,
 }
 
-impl ModelServer {
-    pub fn new(hyperparameters_file: &str) -> Arc<TokioMutex<Option<Self>>> {
-        Arc::new(TokioMutex::new(Some(ModelServer {
-            model: Arc::new(TokioMutex::new(None)),
-            hyperparameters_file: hyperparameters_file.to_string(),
-            notify: None,
-            db: None,
-        })))
+/// Outcome of one readiness check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn pass(detail: Option<String>) -> Self {
+        Check { ok: true, detail }
     }
 
-    pub async fn initialize(
-        &mut self,
+    fn fail(detail: String) -> Self {
+        Check {
+            ok: false,
+            detail: Some(detail),
+        }
+    }
+}
+
+/// Whether the tenant can answer recommendations
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub model: Check,
+    pub database: Check,
+    pub model_age: Check,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServerStatus {
+    pub model: Option<ModelStatus>,
+    /// Current or last training run since the process started
+    pub training: Option<TrainingStatus>,
+    pub database: DatabaseStatus,
+    /// `None` until the catalog is first loaded, or when it's disabled
+    pub catalog: Option<CatalogStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelStatus {
+    pub version: Option<u64>,
+    pub trained_at: Option<DateTime<Utc>>,
+    pub age_secs: Option<i64>,
+    pub num_clients: usize,
+    pub num_products: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseStatus {
+    pub ok: bool,
+    pub pool_size: usize,
+    pub latency_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ModelServer {
+    /// Connects to the tenant's database and loads its catalog and model, training one
+    /// in the background when there is none yet, then watches the model file for new ones
+    pub async fn start(
+        tenant: &Tenant,
+        catalog: &CatalogConfig,
         notify: Arc<Notify>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        self.notify = Some(notify.clone());
-        let db_type = std::env::var("DB_TYPE").expect("DB_TYPE is not set in the environment");
-        let db: Arc<TokioMutex<dyn DatabaseTrait + Send + Sync>> = match db_type.as_str() {
-            "sqlserver" => Arc::new(TokioMutex::new(SqlServerDatabase::new().await)),
-            "firebird" => Arc::new(TokioMutex::new(FirebirdDatabase::new())),
-            _ => panic!("Unsupported DB_TYPE: '{}'", db_type),
-        };
-        self.db = Some(Arc::new(TokioMutex::new(Database::new(db))));
-        if let Ok(json_data) = load_json_data_from_file(&self.hyperparameters_file) {
-            let mut model = ALS::new(
-                json_data.hyperparameters.num_factors,
-                json_data.hyperparameters.regularization,
-                json_data.hyperparameters.confidence_multiplier,
-                1e-4,
-                100,
-                json_data.matrix.clone(),
-            );
-            model.build_from_data(
-                &json_data.client_factors,
-                &json_data.product_factors,
-                &json_data.client_index,
-                &json_data.product_index,
-            );
-            let mut model_lock = self.model.lock().await;
-            *model_lock = Some(model);
-        } else {
-            println!("Hyperparameters file not found, waiting for file creation...");
-            let matrix = self
-                .db
-                .as_ref()
-                .unwrap()
-                .lock()
-                .await
-                .build_matrix()
-                .await
-                .unwrap();
-            let _ = {
-                let notify = self.notify.clone();
-                tokio::spawn(async move { find_best_als_model(matrix, notify.unwrap()).await })
-            };
+    ) -> Result<SharedModelServer, Box<dyn std::error::Error + Send + Sync>> {
+        let server = Arc::new(ModelServer {
+            tenant: tenant.name.clone(),
+            model: Arc::new(ArcSwapOption::empty()),
+            hyperparameters_file: tenant.model_file.clone(),
+            registry: tenant.registry(),
+            notify,
+            db: Database::connect_pool(&tenant.database).await?,
+            catalog: Catalog::default(),
+            catalog_reload: Arc::new(Notify::new()),
+            matrix_file: tenant.matrix_file.clone(),
+            settings: tenant.training.clone(),
+            training: Mutex::new(None),
+            events: TrainingEvents::default(),
+        });
+
+        migrate_legacy_model(&server.hyperparameters_file);
+        match load_served_model(&server.tenant, &server.hyperparameters_file).await {
+            Ok(model) => server.model.store(Some(Arc::new(model))),
+            Err(e) => {
+                info!("No usable model file ({}), waiting for file creation...", e);
+                server.train(HyperparameterGrid::default())?;
+            }
         }
 
-        self.start_file_watcher();
-        return Ok(());
+        if catalog.enabled {
+            server.reload_catalog().await;
+            server.start_catalog_refresh(catalog.refresh_minutes);
+        }
+        server.start_file_watcher();
+        Ok(server)
     }
 
-    pub async fn predict(&self, user_id: &str, n: Option<usize>) -> Option<Recommendation> {
-        let model = self.model.lock().await;
-        if let Some(ref m) = *model {
-            let recommendation = m.recommend(user_id, n);
-            let client = self
-                .db
-                .as_ref()
-                .unwrap()
-                .lock()
-                .await
-                .get_client_by_id(user_id.to_string())
-                .await
-                .unwrap();
-            let products = join_all(recommendation.into_iter().map(|id| {
-                let db = self.db.as_ref().unwrap().clone();
-                async move { db.lock().await.get_product_by_id(id).await }
-            }))
+    pub async fn predict(
+        &self,
+        user_id: &str,
+        n: Option<usize>,
+    ) -> Result<Recommendation, AppError> {
+        // Keep the model loaded at the start of the request, even if a new one is
+        // swapped in meanwhile
+        let model = self.model.load_full().ok_or(AppError::ModelNotLoaded)?;
+        let recommendation = model.recommend(&self.tenant, user_id, n);
+        let client = self
+            .get_client_by_id(user_id.to_string())
             .await
-            .into_iter()
-            .filter_map(Result::ok)
-            .collect::<Vec<_>>();
+            .map_err(|e| match e {
+                DatabaseError::NotFound(_) => AppError::UnknownClient(user_id.to_string()),
+                e => e.into(),
+            })?;
+        let mut rows = self.products_by_id(recommendation.clone()).await?;
+        // In the order of the recommendation; products removed from the catalog since
+        // the model was trained are left out
+        let products = recommendation
+            .iter()
+            .filter_map(|id| rows.remove(id))
+            .collect();
+
+        Ok(Recommendation { client, products })
+    }
+
+    /// Recommendations for many clients, scored in one pass and hydrated with a
+    /// single product query
+    pub async fn recommend_batch(&self, request: BatchRequest) -> Result<BatchResponse, AppError> {
+        if request.clients.is_empty() || request.clients.len() > MAX_BATCH_CLIENTS {
+            return Err(AppError::BadRequest(format!(
+                "a batch needs between 1 and {} clients, not {}",
+                MAX_BATCH_CLIENTS,
+                request.clients.len()
+            )));
+        }
+        if request.options.n == 0 {
+            return Err(AppError::BadRequest("n must be at least 1".to_string()));
+        }
+        let model = self.model.load_full().ok_or(AppError::ModelNotLoaded)?;
+        let scored = model
+            .als
+            .recommend_batch(&request.clients, &request.options);
 
-            return Some(Recommendation { client, products });
-        } else {
-            return None;
+        let product_ids: HashSet<String> = scored.iter().flatten().flatten().cloned().collect();
+        let products = self
+            .products_by_id(product_ids.into_iter().collect())
+            .await?;
+
+        let mut response = BatchResponse {
+            recommendations: Vec::new(),
+            unknown_clients: Vec::new(),
+        };
+        for (client_id, recommended) in request.clients.into_iter().zip(scored) {
+            match recommended {
+                Some(ids) => response.recommendations.push(BatchRecommendation {
+                    client_id,
+                    // Products removed from the catalog since training are left out
+                    products: ids
+                        .iter()
+                        .filter_map(|id| products.get(id).cloned())
+                        .collect(),
+                }),
+                None => response.unknown_clients.push(client_id),
+            }
         }
+        Ok(response)
     }
 
-    pub async fn get_metadata(&self) -> MetadataModel {
-        let model = self.model.lock().await;
-        if let Some(ref m) = *model {
-            MetadataModel {
-                num_factors: m.num_factors,
-                regularization: m.regularization,
-                confidence_multiplier: m.confidence_multiplier,
-                epr: m.compute_epr().unwrap_or(0.0),
+    /// Rows of the products among `ids`, from the catalog and a single query for the
+    /// ones it misses
+    async fn products_by_id(
+        &self,
+        ids: Vec<String>,
+    ) -> Result<HashMap<String, ProductRow>, DatabaseError> {
+        let mut products = HashMap::new();
+        let mut missing = Vec::new();
+        for id in ids {
+            match self.catalog.product(&id) {
+                Some(product) => {
+                    products.insert(id, product);
+                }
+                None => missing.push(id),
             }
-        } else {
-            MetadataModel {
+        }
+        products.extend(
+            self.db
+                .get_products_by_ids(missing)
+                .await?
+                .into_iter()
+                .map(|product| (product.id.clone(), product)),
+        );
+        Ok(products)
+    }
+
+    pub fn has_model(&self) -> bool {
+        self.model.load().is_some()
+    }
+
+    pub async fn get_metadata(&self) -> MetadataModel {
+        match self.model.load_full() {
+            Some(model) => MetadataModel {
+                version: model.metadata.version,
+                trained_at: model.metadata.trained_at,
+                num_factors: model.als.num_factors,
+                regularization: model.als.regularization,
+                confidence_multiplier: model.als.confidence_multiplier,
+                epr: model.epr().unwrap_or(0.0),
+            },
+            None => MetadataModel {
+                version: None,
+                trained_at: None,
                 num_factors: 0,
                 regularization: 0.0,
                 confidence_multiplier: 0.0,
                 epr: 0.0,
+            },
+        }
+    }
+
+    /// Checks the model is loaded and recent enough and the database answers
+    pub async fn readiness(&self) -> Readiness {
+        let model = self.model.load_full();
+        let model_check = match &model {
+            Some(_) => Check::pass(None),
+            None => Check::fail("no model loaded".to_string()),
+        };
+        let max_age = self.settings.max_model_age_hours;
+        let model_age = match model.as_ref().map(|model| model.metadata.trained_at) {
+            None => Check::fail("no model loaded".to_string()),
+            Some(None) => Check::pass(Some("training date unknown".to_string())),
+            Some(Some(trained_at)) => {
+                let age = Utc::now() - trained_at;
+                let detail = format!("trained {} hours ago", age.num_hours());
+                if max_age > 0 && age.num_hours() >= max_age as i64 {
+                    Check::fail(format!("{}, limit is {}", detail, max_age))
+                } else {
+                    Check::pass(Some(detail))
+                }
             }
+        };
+        let database = match self.db.ping().await {
+            Ok(latency) => Check::pass(Some(format!("{} ms", latency.as_millis()))),
+            Err(e) => Check::fail(e.to_string()),
+        };
+        Readiness {
+            ready: model_check.ok && database.ok && model_age.ok,
+            model: model_check,
+            database,
+            model_age,
+        }
+    }
+
+    /// Served model, last training run and database latency
+    pub async fn status(&self) -> ServerStatus {
+        let model = self.model.load_full().map(|model| ModelStatus {
+            version: model.metadata.version,
+            trained_at: model.metadata.trained_at,
+            age_secs: model
+                .metadata
+                .trained_at
+                .map(|trained_at| (Utc::now() - trained_at).num_seconds()),
+            num_clients: model
+                .als
+                .client_index
+                .as_ref()
+                .map_or(0, |index| index.len()),
+            num_products: model
+                .als
+                .product_index
+                .as_ref()
+                .map_or(0, |index| index.len()),
+        });
+        let ping = self.db.ping().await;
+        ServerStatus {
+            model,
+            training: self.training_status(),
+            database: DatabaseStatus {
+                ok: ping.is_ok(),
+                pool_size: self.db.pool_size(),
+                latency_ms: ping
+                    .as_ref()
+                    .ok()
+                    .map(|latency| latency.as_secs_f64() * 1000.0),
+                error: ping.err().map(|e| e.to_string()),
+            },
+            catalog: self.catalog.status(),
         }
     }
 
@@ -150,13 +453,9 @@ impl ModelServer {
         search: String,
         page: i64,
     ) -> Result<ClientPage, DatabaseError> {
-        if let Some(ref db) = self.db {
-            let mut db = db.lock().await;
-            db.get_clients(search, page).await
-        } else {
-            Err(DatabaseError::ConnectionError(
-                "Database not initialized".to_string(),
-            ))
+        match self.catalog.search_clients(&search, page) {
+            Some(clients) => Ok(clients),
+            None => self.db.get_clients(search, page).await,
         }
     }
 
@@ -165,41 +464,233 @@ impl ModelServer {
         search: String,
         page: i64,
     ) -> Result<ProductPage, DatabaseError> {
-        if let Some(ref db) = self.db {
-            let mut db = db.lock().await;
-            let products = db.get_products(search, page).await;
-            products
-        } else {
-            Err(DatabaseError::ConnectionError(
-                "Database not initialized".to_string(),
-            ))
+        match self.catalog.search_products(&search, page) {
+            Some(products) => Ok(products),
+            None => self.db.get_products(search, page).await,
         }
     }
 
+    /// From the catalog, or the database for clients added since it was loaded
     pub async fn get_client_by_id(&self, client_id: String) -> Result<ClientRow, DatabaseError> {
-        if let Some(ref db) = self.db {
-            let mut db = db.lock().await;
-            let client = db.get_client_by_id(client_id).await;
-            client
-        } else {
-            Err(DatabaseError::ConnectionError(
-                "Database not initialized".to_string(),
-            ))
+        match self.catalog.client(&client_id) {
+            Some(client) => Ok(client),
+            None => self.db.get_client_by_id(client_id).await,
         }
     }
 
+    /// From the catalog, or the database for products added since it was loaded
     pub async fn get_product_by_id(&self, product_id: String) -> Result<ProductRow, DatabaseError> {
-        if let Some(ref db) = self.db {
-            let mut db = db.lock().await;
-            let product = db.get_product_by_id(product_id).await;
-            product
-        } else {
-            Err(DatabaseError::ConnectionError(
-                "Database not initialized".to_string(),
-            ))
+        match self.catalog.product(&product_id) {
+            Some(product) => Ok(product),
+            None => self.db.get_product_by_id(product_id).await,
         }
     }
 
+    /// Loads the catalog again, keeping the current one if the database fails
+    async fn reload_catalog(&self) {
+        let start = Instant::now();
+        match self.catalog.load(&self.db).await {
+            Ok(()) => {
+                let status = self.catalog.status();
+                info!(
+                    clients = status.as_ref().map_or(0, |status| status.clients),
+                    products = status.as_ref().map_or(0, |status| status.products),
+                    "Catalog loaded in {:?}",
+                    start.elapsed()
+                );
+            }
+            Err(e) => warn!("Failed to load the catalog, keeping the current one: {}", e),
+        }
+    }
+
+    /// Reloads the catalog every `refresh_minutes` (never if 0) and whenever a new
+    /// model is served, until shutdown
+    fn start_catalog_refresh(self: &Arc<Self>, refresh_minutes: u64) {
+        let server = self.clone();
+        let interval = Duration::from_secs(refresh_minutes * 60);
+        let span = info_span!("catalog", tenant = %self.tenant);
+        tokio::spawn(
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = server.notify.notified() => break,
+                        _ = server.catalog_reload.notified() => {}
+                        _ = tokio::time::sleep(interval), if refresh_minutes > 0 => {}
+                    }
+                    server.reload_catalog().await;
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    pub async fn list_versions(&self) -> Result<ModelVersions, AppError> {
+        self.with_registry(|registry| registry.list()).await
+    }
+
+    /// Serves another version; the file watcher picks up the swapped model file
+    pub async fn activate_version(&self, version: u64) -> Result<ModelVersion, AppError> {
+        self.with_registry(move |registry| registry.activate(version))
+            .await
+    }
+
+    pub async fn rollback(&self) -> Result<ModelVersion, AppError> {
+        self.with_registry(|registry| registry.rollback()).await
+    }
+
+    /// Runs a registry operation on the blocking pool: it waits on the registry lock
+    /// and reads, checks and copies whole model files
+    async fn with_registry<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&ModelRegistry) -> Result<T, AppError> + Send + 'static,
+    ) -> Result<T, AppError> {
+        let registry = self.registry.clone();
+        tokio::task::spawn_blocking(move || operation(&registry))
+            .await
+            .map_err(|e| AppError::Internal(format!("registry task failed: {}", e)))?
+    }
+
+    /// Starts a training run in the background; the best model is published through
+    /// the registry and picked up by the file watcher. Only one run at a time.
+    pub fn train(self: &Arc<Self>, grid: HyperparameterGrid) -> Result<Arc<TrainingRun>, AppError> {
+        grid.validate().map_err(AppError::BadRequest)?;
+        self.start_training(TrainingPlan::Search(grid))
+    }
+
+    /// Retrains the served model's hyperparameters for a few iterations, starting from
+    /// its factors, so only new clients and products start from scratch
+    pub fn refit(self: &Arc<Self>) -> Result<Arc<TrainingRun>, AppError> {
+        if !self.has_model() {
+            return Err(AppError::NotFound("model to refit".to_string()));
+        }
+        self.start_training(TrainingPlan::Refit)
+    }
+
+    fn start_training(self: &Arc<Self>, plan: TrainingPlan) -> Result<Arc<TrainingRun>, AppError> {
+        let run = {
+            let mut training = self.training.lock().unwrap();
+            if training.as_ref().is_some_and(|run| run.is_running()) {
+                return Err(AppError::Conflict(
+                    "A training run is already in progress".to_string(),
+                ));
+            }
+            let run = TrainingRun::new(self.events.clone());
+            *training = Some(run.clone());
+            run
+        };
+
+        let server = self.clone();
+        let task_run = run.clone();
+        let refit = matches!(plan, TrainingPlan::Refit);
+        let span = info_span!("training", tenant = %self.tenant, refit);
+        tokio::spawn(
+            async move {
+                let (grid, warm_start) = match plan {
+                    TrainingPlan::Search(grid) => (grid, None),
+                    TrainingPlan::Refit => match server.load_warm_start().await {
+                        Ok((grid, warm_start)) => {
+                            info!(
+                                "Refitting {:?} for {} iterations",
+                                warm_start.hyperparameters, grid.max_iterations
+                            );
+                            (grid, Some(warm_start))
+                        }
+                        Err(e) => {
+                            error!("No model to refit: {}", e);
+                            task_run
+                                .finish(TrainingState::Failed, format!("No model to refit: {}", e));
+                            return;
+                        }
+                    },
+                };
+                let matrix = match load_matrix(
+                    &server.db,
+                    &server.matrix_file,
+                    server.settings.matrix_rebuild_days,
+                )
+                .await
+                {
+                    Ok(matrix) => matrix,
+                    Err(e) => {
+                        error!("Failed to build matrix: {}", e);
+                        task_run.finish(
+                            TrainingState::Failed,
+                            format!("Failed to build matrix: {}", e),
+                        );
+                        return;
+                    }
+                };
+                // The grid search keeps every rayon thread busy for the whole run, so
+                // it stays off the runtime's workers
+                let training = {
+                    let run = task_run.clone();
+                    let server = server.clone();
+                    let span = Span::current();
+                    tokio::task::spawn_blocking(move || {
+                        let _span = span.enter();
+                        find_best_als_model(
+                            matrix,
+                            &grid,
+                            warm_start,
+                            &run,
+                            &server.registry,
+                            &server.settings.gate,
+                        );
+                    })
+                };
+                // A panicking run must not stay "running" and block the next ones
+                if let Err(e) = training.await {
+                    task_run.finish(TrainingState::Failed, format!("Training failed: {}", e));
+                }
+                let status = task_run.status();
+                metrics::record_training(
+                    &server.tenant,
+                    status.state.name(),
+                    Duration::from_secs_f64(status.elapsed_secs),
+                );
+            }
+            .instrument(span),
+        );
+        Ok(run)
+    }
+
+    /// Reads the served model file on the blocking pool, with the grid that refits its
+    /// hyperparameters
+    async fn load_warm_start(
+        &self,
+    ) -> Result<(HyperparameterGrid, Arc<JSONData>), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.hyperparameters_file.clone();
+        let iterations = self.settings.refit_iterations;
+        tokio::task::spawn_blocking(move || {
+            let (json_data, _) = load_model(&path)?;
+            let grid = HyperparameterGrid::single(&json_data.hyperparameters, iterations);
+            Ok((grid, Arc::new(json_data)))
+        })
+        .await?
+    }
+
+    /// Status of the current or last training run
+    pub fn training_status(&self) -> Option<TrainingStatus> {
+        self.training
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|run| run.status())
+    }
+
+    pub fn training_events(&self) -> &TrainingEvents {
+        &self.events
+    }
+
+    /// Cancels the training run in progress, if any
+    pub fn cancel_training(&self) -> Option<TrainingStatus> {
+        let training = self.training.lock().unwrap();
+        let run = training.as_ref().filter(|run| run.is_running())?;
+        warn!("Cancelling training run");
+        run.cancel();
+        Some(run.status())
+    }
+
     fn start_file_watcher(&self) {
         let hyperparameters_path = self.hyperparameters_file.clone();
         let hyperparameters_dir = Path::new(&hyperparameters_path)
@@ -215,7 +706,17 @@ impl ModelServer {
 
         let model = self.model.clone();
         let notify = self.notify.clone();
+        let catalog_reload = self.catalog_reload.clone();
+        let tenant = self.tenant.clone();
+
+        if let Err(e) = fs::create_dir_all(&hyperparameters_dir) {
+            error!(
+                "Failed to create directory {:?}: {:?}",
+                hyperparameters_dir, e
+            );
+        }
 
+        let span = info_span!("model_watcher", tenant = %tenant);
         tokio::spawn(async move {
             let (tx, mut rx) = mpsc::channel(1);
             let mut watcher = match recommended_watcher(move |res| {
@@ -223,22 +724,21 @@ impl ModelServer {
             }) {
                 Ok(watcher) => watcher,
                 Err(e) => {
-                    eprintln!("Failed to create watcher: {:?}", e);
+                    error!("Failed to create watcher: {:?}", e);
                     return;
                 }
             };
 
             if let Err(e) = watcher.watch(&hyperparameters_dir, RecursiveMode::NonRecursive) {
-                eprintln!("Failed to watch directory: {:?}", e);
+                error!("Failed to watch directory: {:?}", e);
                 return;
             }
 
-            println!("Started watching directory: {:?}", hyperparameters_dir);
-            let notify = notify.as_ref().unwrap().clone();
+            info!("Started watching directory: {:?}", hyperparameters_dir);
             loop {
                 tokio::select! {
                     _ = notify.notified() => {
-                        println!("File watcher received shutdown signal.");
+                        info!("File watcher received shutdown signal.");
                         break;
                     }
                     res = rx.recv() => {
@@ -251,53 +751,84 @@ impl ModelServer {
                                     .any(|path| path.ends_with(&hyperparameters_file))
                                     && (matches!(event.kind, EventKind::Modify(_)) || matches!(event.kind, EventKind::Create(_)))
                                 {
-                                    println!("Hyperparameters file changed or created, reloading model...");
-                                    // Add a delay to allow the file writing process to complete
-                                    sleep(Duration::from_millis(500)).await;
-                                    match load_json_data_from_file(&hyperparameters_path) {
-                                        Ok(json_data) => {
-                                            let mut model = model.lock().await;
-                                            *model = Some(ALS::new(
-                                                json_data.hyperparameters.num_factors,
-                                                json_data.hyperparameters.regularization,
-                                                json_data.hyperparameters.confidence_multiplier,
-                                                1e-4,
-                                                200,
-                                                json_data.matrix.clone(),
-                                            ));
-                                            if let Some(ref mut m) = *model {
-                                                m.build_from_data(
-                                                    &json_data.client_factors,
-                                                    &json_data.product_factors,
-                                                    &json_data.client_index,
-                                                    &json_data.product_index,
-                                                );
-                                            }
-                                            println!("Model reloaded successfully.");
+                                    info!("Model file changed or created, reloading model...");
+                                    // Models are published by renaming a complete file over
+                                    // this one; anything else (e.g. a copy still in progress)
+                                    // fails validation and the current model is kept
+                                    match load_served_model(&tenant, &hyperparameters_path).await {
+                                        Ok(served) => {
+                                            model.store(Some(Arc::new(served)));
+                                            info!("Model reloaded successfully.");
+                                            // New clients and products come with a new model
+                                            catalog_reload.notify_one();
                                         }
                                         Err(e) => {
-                                            println!("Failed to reload model: {:?}", e);
+                                            warn!("Rejected model file, keeping the current model: {}", e);
                                         }
                                     }
                                 }
                             }
-                            Some(Err(e)) => println!("Watch error: {:?}", e),
+                            Some(Err(e)) => warn!("Watch error: {:?}", e),
                             None => {
-                                println!("Channel closed");
+                                info!("Channel closed");
                                 break;
                             }
                         }
                     }
                 }
             }
-        });
+        }
+        .instrument(span));
     }
 }
 
-fn load_json_data_from_file(
-    file_path: &str,
-) -> Result<JSONData, Box<dyn std::error::Error + Send + Sync>> {
-    let file = File::open(file_path)?;
-    let json_data = serde_json::from_reader(file)?;
-    Ok(json_data)
+pub fn build_model(json_data: &JSONData, max_iterations: usize) -> ALS {
+    let mut model = ALS::new(
+        json_data.hyperparameters.num_factors,
+        json_data.hyperparameters.regularization,
+        json_data.hyperparameters.confidence_multiplier,
+        1e-4,
+        max_iterations,
+        json_data.matrix.clone(),
+    );
+    model.build_from_data(
+        &json_data.client_factors,
+        &json_data.product_factors,
+        &json_data.client_index,
+        &json_data.product_index,
+    );
+    model
+}
+
+/// Loads and builds the model in `path` on the blocking pool, recording how long it
+/// took
+async fn load_served_model(
+    tenant: &str,
+    path: &str,
+) -> Result<ServedModel, Box<dyn std::error::Error + Send + Sync>> {
+    let (tenant, path) = (tenant.to_string(), path.to_string());
+    tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let (json_data, metadata) = load_model(&path)?;
+        let als = build_model(&json_data, 100);
+        let load_time = start.elapsed();
+        let model = ServedModel::new(als, metadata);
+        metrics::record_model(&tenant, &model, load_time);
+        Ok(model)
+    })
+    .await?
+}
+
+/// Converts a `hyperparameters.json` left by older versions next to `model_file`, so
+/// upgrading doesn't force a retrain
+fn migrate_legacy_model(model_file: &str) {
+    let model_path = Path::new(model_file);
+    let legacy_path = model_path.with_file_name("hyperparameters.json");
+    if model_path.exists() || !legacy_path.exists() || legacy_path == model_path {
+        return;
+    }
+    match convert_model(&legacy_path.to_string_lossy(), model_file) {
+        Ok(_) => info!("Converted {:?} to {}", legacy_path, model_file),
+        Err(e) => error!("Failed to convert {:?}: {}", legacy_path, e),
+    }
 }
@@ -1,44 +1,48 @@
 use crate::models::db::{
-    ClientPage, ClientProductMatrix, ClientRow, DatabaseTrait, ProductPage, ProductRow,
+    required, ClientPage, ClientProductMatrix, ClientRow, DatabaseError, DatabaseSettings,
+    DatabaseTrait, MatrixDelta, ProductPage, ProductRow, Watermark,
 };
+use crate::services::queries::{Dialect, QueryBuilder, MAX_IN_LIST};
 use async_trait::async_trait;
 use futures::TryStreamExt;
 use std::collections::HashMap;
-use std::env;
 use tiberius::{AuthMethod, Client, Config};
 use tokio::net::TcpStream;
 use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
 
 pub struct SqlServerDatabase {
     client: Option<Client<Compat<TcpStream>>>,
+    queries: QueryBuilder,
 }
 
 impl SqlServerDatabase {
-    pub async fn new() -> Self {
-        dotenv::dotenv().ok();
-
+    pub async fn new(settings: &DatabaseSettings) -> Result<Self, DatabaseError> {
+        let connection_error = |e: String| DatabaseError::ConnectionError(e);
         let mut config = Config::new();
-        config.host(env::var("DB_HOST").expect("DB_HOST is not set"));
-        config.port(
-            env::var("DB_PORT")
-                .expect("DB_PORT is not set")
-                .parse::<u16>()
-                .unwrap(),
-        );
+        config.host(required(&settings.host, "host").map_err(connection_error)?);
+        config.port(*required(&settings.port, "port").map_err(connection_error)?);
         config.authentication(AuthMethod::sql_server(
-            env::var("DB_USERNAME").expect("DB_USERNAME is not set"),
-            env::var("DB_PASSWORD").expect("DB_PASSWORD is not set"),
+            required(&settings.username, "username").map_err(connection_error)?,
+            required(&settings.password, "password").map_err(connection_error)?,
         ));
-        config.database(env::var("DB_NAME").expect("DB_NAME is not set"));
+        config.database(required(&settings.name, "name").map_err(connection_error)?);
         config.trust_cert();
 
-        let tcp = TcpStream::connect(config.get_addr()).await.unwrap();
-        tcp.set_nodelay(true).unwrap();
-        let client = Client::connect(config, tcp.compat_write()).await.unwrap();
-
-        SqlServerDatabase {
+        let connection_error = |e: &dyn std::fmt::Display| {
+            DatabaseError::ConnectionError(format!("Failed to connect to SQL Server: {}", e))
+        };
+        let tcp = TcpStream::connect(config.get_addr())
+            .await
+            .map_err(|e| connection_error(&e))?;
+        tcp.set_nodelay(true).map_err(|e| connection_error(&e))?;
+        let client = Client::connect(config, tcp.compat_write())
+            .await
+            .map_err(|e| connection_error(&e))?;
+
+        Ok(SqlServerDatabase {
             client: Some(client),
-        }
+            queries: QueryBuilder::new(settings.schema.clone(), Dialect::SqlServer),
+        })
     }
 }
 
@@ -47,104 +51,84 @@ impl DatabaseTrait for SqlServerDatabase {
     async fn build_client_product_matrix(
         &mut self,
     ) -> Result<ClientProductMatrix, Box<dyn std::error::Error>> {
-        // Your SQL Server specific code
-        let table_inve = env::var("TABLE_INVE").expect("TABLE_INVE is not set");
-        let table_fact = env::var("TABLE_FACT").expect("TABLE_FACT is not set");
-        let table_par_fact = env::var("TABLE_PAR_FACT").expect("TABLE_PAR_FACT is not set");
-        let table_client = env::var("TABLE_CLIENT").expect("TABLE_CLIENT is not set");
-
-        let excluded_clients: Vec<String> = env::var("EXCLUDED_CLIENTS")
-            .unwrap_or_default()
-            .split(',')
-            .map(|s| format!("'{}'", s.trim()))
-            .collect();
-
-        let excluded_clients_clause = if !excluded_clients.is_empty() {
-            format!("AND F.CVE_CLPV NOT IN ({})", excluded_clients.join(", "))
-        } else {
-            String::new()
+        let query = self.queries.interactions();
+        let query_clients = self.queries.all_clients();
+        let query_products = self.queries.active_products();
+
+        let client = self.client.as_mut().unwrap();
+
+        let mut matrix = query_matrix(client, query).await?;
+        add_catalog(client, &mut matrix, query_clients, query_products).await?;
+
+        Ok(matrix)
+    }
+
+    async fn get_watermark(&mut self) -> Result<Option<Watermark>, Box<dyn std::error::Error>> {
+        let query = match self.queries.watermark() {
+            Some(query) => query,
+            None => return Ok(None),
         };
+        let client = self.client.as_mut().unwrap();
+        let watermark = client
+            .query(query, &[])
+            .await?
+            .into_row()
+            .await?
+            .map(|row| Watermark {
+                date: row.get::<&str, _>(0).unwrap_or_default().to_string(),
+                document: row.get::<&str, _>(1).unwrap_or_default().to_string(),
+                extracted_on: None,
+            });
+        Ok(watermark)
+    }
 
-        let query = format!(
-                    "SELECT F.CVE_CLPV AS CLIENT_ID, PF.CVE_ART AS PRODUCT_ID, SUM(PF.CANT) AS TOTAL_QUANTITY
-                     FROM dbo.{} AS PF
-                     INNER JOIN dbo.{} AS I ON PF.CVE_ART = I.CVE_ART
-                     INNER JOIN dbo.{} AS C INNER JOIN dbo.{} AS F ON C.CLAVE = F.CVE_CLPV
-                     ON PF.CVE_DOC = F.CVE_DOC WHERE F.STATUS <> 'C'
-                     AND C.NOMBRE NOT LIKE '%PUBLICO EN GENERAL%' {}
-                     AND I.STATUS = 'A'
-                     GROUP BY F.CVE_CLPV, PF.CVE_ART;",
-                    table_par_fact, table_inve, table_client, table_fact, excluded_clients_clause
-                );
-
-        let query_clients = format!(
-            "SELECT C.CLAVE AS CLIENT_ID
-             FROM dbo.{} AS C;",
-            table_client
-        );
-
-        let query_products = format!(
-            "SELECT I.CVE_ART AS PRODUCT_ID
-             FROM dbo.{} AS I
-             WHERE I.STATUS = 'A';",
-            table_inve
-        );
+    async fn fetch_matrix_delta(
+        &mut self,
+        since: Option<&Watermark>,
+        until: &Watermark,
+    ) -> Result<MatrixDelta, Box<dyn std::error::Error>> {
+        let query = self
+            .queries
+            .interactions_between(since, until)
+            .ok_or("Incremental extraction requires an invoice date column")?;
+        let query_cancelled = match since {
+            Some(since) => self
+                .queries
+                .cancelled_interactions(since, since.extracted_on.as_deref()),
+            None => self.queries.cancelled_interactions(until, None),
+        };
+        let query_clients = self.queries.all_clients();
+        let query_products = self.queries.active_products();
 
         let client = self.client.as_mut().unwrap();
 
-        let mut matrix: ClientProductMatrix = {
-            let mut result = client.query(query, &[]).await?;
-            let mut matrix = HashMap::new();
+        let mut added = query_matrix(client, query).await?;
+        if since.is_none() {
+            add_catalog(client, &mut added, query_clients, query_products).await?;
+        }
 
-            while let Some(item) = result.try_next().await? {
-                if let Some(row) = item.into_row() {
-                    let client_id: String = row
-                        .get::<&str, _>(0)
-                        .unwrap_or("unknown_client")
-                        .to_string();
-                    let product_id: String = row
-                        .get::<&str, _>(1)
-                        .unwrap_or("unknown_product")
-                        .to_string();
-                    let total_quantity: f64 = row.get::<f64, _>(2).unwrap_or(0.0);
-                    matrix
-                        .entry(client_id)
-                        .or_insert_with(HashMap::new)
-                        .insert(product_id, total_quantity);
-                }
-            }
-            matrix
+        let cancelled = match query_cancelled {
+            Some(query_cancelled) => query_documents(client, query_cancelled).await?,
+            None => HashMap::new(),
         };
 
-        {
-            let mut result_clients = client.query(query_clients, &[]).await?;
-            while let Some(item) = result_clients.try_next().await? {
-                if let Some(row) = item.into_row() {
-                    let client_id: String = row
-                        .get::<&str, _>(0)
-                        .unwrap_or("unknown_client")
-                        .to_string();
-                    matrix.entry(client_id).or_insert_with(HashMap::new);
-                }
-            }
-        }
+        Ok(MatrixDelta { added, cancelled })
+    }
 
-        {
-            let mut result_products = client.query(query_products, &[]).await?;
-            while let Some(item) = result_products.try_next().await? {
-                if let Some(row) = item.into_row() {
-                    let product_id: String = row
-                        .get::<&str, _>(0)
-                        .unwrap_or("unknown_product")
-                        .to_string();
-                    if let Some(client_products) = matrix.values_mut().next() {
-                        client_products.entry(product_id.clone()).or_insert(0.0);
-                    }
-                }
+    async fn fetch_returns(
+        &mut self,
+        since: Option<&str>,
+    ) -> Result<HashMap<String, ClientProductMatrix>, Box<dyn std::error::Error>> {
+        let queries = self.queries.returned_interactions(since);
+        let client = self.client.as_mut().unwrap();
+
+        let mut returns = HashMap::new();
+        for (table, query) in queries {
+            for (document, interactions) in query_documents(client, query).await? {
+                returns.insert(format!("{}:{}", table, document), interactions);
             }
         }
-
-        Ok(matrix)
+        Ok(returns)
     }
 
     async fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
@@ -154,44 +138,23 @@ impl DatabaseTrait for SqlServerDatabase {
         Ok(())
     }
 
+    async fn ping(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let client = self.client.as_mut().ok_or("Connection closed")?;
+        client
+            .simple_query("SELECT 1")
+            .await?
+            .into_results()
+            .await?;
+        Ok(())
+    }
+
     async fn get_clients(
         &mut self,
         search: String,
         page: i64,
     ) -> Result<ClientPage, Box<dyn std::error::Error>> {
-        let table_client = env::var("TABLE_CLIENT").expect("TABLE_CLIENT is not set");
-        let excluded_clients: Vec<String> = env::var("EXCLUDED_CLIENTS")
-            .unwrap_or_default()
-            .split(',')
-            .map(|s| format!("'{}'", s.trim()))
-            .collect();
-
-        let excluded_clients_clause = if !excluded_clients.is_empty() {
-            format!("AND CLAVE NOT IN ({})", excluded_clients.join(", "))
-        } else {
-            String::new()
-        };
-
-        let offset = (page - 1) * 10;
-
-        let query1 = format!(
-            "SELECT CLAVE as id, NOMBRE as name, EMAILPRED as email
-             FROM dbo.{}
-             WHERE NOMBRE LIKE '%{}%'
-             AND NOMBRE NOT LIKE '%PUBLICO EN GENERAL%' {}
-             ORDER BY CLAVE
-             OFFSET {} ROWS
-             FETCH NEXT 10 ROWS ONLY;",
-            table_client, search, excluded_clients_clause, offset,
-        );
-
-        let query2 = format!(
-            "SELECT (COUNT(*)-1)/10+1 as total_pages
-             FROM dbo.{}
-             WHERE NOMBRE LIKE '%{}%'
-             AND NOMBRE NOT LIKE '%PUBLICO EN GENERAL%' {}",
-            table_client, search, excluded_clients_clause,
-        );
+        let query1 = self.queries.clients_page(&search, page);
+        let query2 = self.queries.clients_total_pages(&search);
 
         let client = self.client.as_mut().unwrap();
 
@@ -229,28 +192,8 @@ impl DatabaseTrait for SqlServerDatabase {
         search: String,
         page: i64,
     ) -> Result<ProductPage, Box<dyn std::error::Error>> {
-        let table_inve = env::var("TABLE_INVE").expect("TABLE_INVE is not set");
-
-        let query1 = format!(
-            "SELECT CVE_ART as id, DESCR as description, ULT_COSTO as price
-                FROM dbo.{} as I
-                WHERE DESCR LIKE '%{}%'
-                AND I.STATUS = 'A'
-                ORDER BY CVE_ART
-                OFFSET {} ROWS
-                FETCH NEXT 10 ROWS ONLY;",
-            table_inve,
-            search,
-            (page - 1) * 10,
-        );
-
-        let query2 = format!(
-            "SELECT (COUNT(*)-1)/10+1 as total_pages
-                FROM dbo.{} as I
-                WHERE DESCR LIKE '%{}%'
-                AND I.STATUS = 'A';",
-            table_inve, search,
-        );
+        let query1 = self.queries.products_page(&search, page);
+        let query2 = self.queries.products_total_pages(&search);
 
         let client = self.client.as_mut().unwrap();
 
@@ -295,13 +238,7 @@ impl DatabaseTrait for SqlServerDatabase {
         &mut self,
         id: String,
     ) -> Result<ClientRow, Box<dyn std::error::Error>> {
-        let table_client = env::var("TABLE_CLIENT").expect("TABLE_CLIENT is not set");
-        let query = format!(
-            "SELECT CLAVE as id, NOMBRE as name, EMAILPRED as email
-             FROM dbo.{}
-             WHERE CLAVE = '{}';",
-            table_client, id
-        );
+        let query = self.queries.client_by_id(&id);
         let client = self.client.as_mut().unwrap();
         let client_row = client
             .query(query, &[])
@@ -314,7 +251,7 @@ impl DatabaseTrait for SqlServerDatabase {
                 let email: String = row.get::<&str, _>(2).unwrap_or("unknown_email").to_string();
                 ClientRow { id, name, email }
             })
-            .ok_or_else(|| "Client not found".into());
+            .ok_or_else(|| DatabaseError::NotFound(format!("client {}", id)).into());
         client_row
     }
 
@@ -322,13 +259,7 @@ impl DatabaseTrait for SqlServerDatabase {
         &mut self,
         id: String,
     ) -> Result<ProductRow, Box<dyn std::error::Error>> {
-        let table_inve = env::var("TABLE_INVE").expect("TABLE_INVE is not set");
-        let query = format!(
-            "SELECT CVE_ART as id, DESCR as description, ULT_COSTO as price
-             FROM dbo.{}
-             WHERE CVE_ART = '{}';",
-            table_inve, id
-        );
+        let query = self.queries.product_by_id(&id);
         let client = self.client.as_mut().unwrap();
         let client_row = client
             .query(query, &[])
@@ -346,7 +277,166 @@ impl DatabaseTrait for SqlServerDatabase {
                     price,
                 }
             })
-            .ok_or_else(|| "Client not found".into());
+            .ok_or_else(|| DatabaseError::NotFound(format!("product {}", id)).into());
         client_row
     }
+
+    async fn get_all_clients(&mut self) -> Result<Vec<ClientRow>, Box<dyn std::error::Error>> {
+        let query = self.queries.client_catalog();
+        let client = self.client.as_mut().unwrap();
+        let mut result = client.query(query, &[]).await?;
+        let mut clients = Vec::new();
+        while let Some(item) = result.try_next().await? {
+            if let Some(row) = item.into_row() {
+                let id: String = row.get::<&str, _>(0).unwrap_or("unknown_id").to_string();
+                let name: String = row.get::<&str, _>(1).unwrap_or("unknown_name").to_string();
+                let email: String = row.get::<&str, _>(2).unwrap_or("unknown_email").to_string();
+                clients.push(ClientRow { id, name, email });
+            }
+        }
+        Ok(clients)
+    }
+
+    async fn get_all_products(&mut self) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>> {
+        let query = self.queries.product_catalog();
+        let client = self.client.as_mut().unwrap();
+        let mut result = client.query(query, &[]).await?;
+        let mut products = Vec::new();
+        while let Some(item) = result.try_next().await? {
+            if let Some(row) = item.into_row() {
+                let id: String = row.get::<&str, _>(0).unwrap_or("unknown_id").to_string();
+                let description: String =
+                    row.get::<&str, _>(1).unwrap_or("unknown_name").to_string();
+                let price: f64 = row.get::<f64, _>(2).unwrap_or(0.0);
+                products.push(ProductRow {
+                    id,
+                    description,
+                    price,
+                });
+            }
+        }
+        Ok(products)
+    }
+
+    async fn get_products_by_ids(
+        &mut self,
+        ids: Vec<String>,
+    ) -> Result<Vec<ProductRow>, Box<dyn std::error::Error>> {
+        let client = self.client.as_mut().unwrap();
+        let mut products = Vec::with_capacity(ids.len());
+        for chunk in ids.chunks(MAX_IN_LIST) {
+            let query = self.queries.products_by_ids(chunk);
+            let mut result = client.query(query, &[]).await?;
+            while let Some(item) = result.try_next().await? {
+                if let Some(row) = item.into_row() {
+                    let id: String = row.get::<&str, _>(0).unwrap_or("unknown_id").to_string();
+                    let description: String =
+                        row.get::<&str, _>(1).unwrap_or("unknown_name").to_string();
+                    let price: f64 = row.get::<f64, _>(2).unwrap_or(0.0);
+                    products.push(ProductRow {
+                        id,
+                        description,
+                        price,
+                    });
+                }
+            }
+        }
+        Ok(products)
+    }
+}
+
+async fn query_matrix(
+    client: &mut Client<Compat<TcpStream>>,
+    query: String,
+) -> Result<ClientProductMatrix, Box<dyn std::error::Error>> {
+    let mut result = client.query(query, &[]).await?;
+    let mut matrix = HashMap::new();
+
+    while let Some(item) = result.try_next().await? {
+        if let Some(row) = item.into_row() {
+            let client_id: String = row
+                .get::<&str, _>(0)
+                .unwrap_or("unknown_client")
+                .to_string();
+            let product_id: String = row
+                .get::<&str, _>(1)
+                .unwrap_or("unknown_product")
+                .to_string();
+            let total_quantity: f64 = row.get::<f64, _>(2).unwrap_or(0.0);
+            matrix
+                .entry(client_id)
+                .or_insert_with(HashMap::new)
+                .insert(product_id, total_quantity);
+        }
+    }
+    Ok(matrix)
+}
+
+/// Runs a query returning DOCUMENT, CLIENT_ID, PRODUCT_ID and TOTAL_QUANTITY rows and
+/// groups the interactions by document
+async fn query_documents(
+    client: &mut Client<Compat<TcpStream>>,
+    query: String,
+) -> Result<HashMap<String, ClientProductMatrix>, Box<dyn std::error::Error>> {
+    let mut result = client.query(query, &[]).await?;
+    let mut documents: HashMap<String, ClientProductMatrix> = HashMap::new();
+    while let Some(item) = result.try_next().await? {
+        if let Some(row) = item.into_row() {
+            let document: String = row.get::<&str, _>(0).unwrap_or_default().to_string();
+            let client_id: String = row
+                .get::<&str, _>(1)
+                .unwrap_or("unknown_client")
+                .to_string();
+            let product_id: String = row
+                .get::<&str, _>(2)
+                .unwrap_or("unknown_product")
+                .to_string();
+            let total_quantity: f64 = row.get::<f64, _>(3).unwrap_or(0.0);
+            documents
+                .entry(document)
+                .or_default()
+                .entry(client_id)
+                .or_default()
+                .insert(product_id, total_quantity);
+        }
+    }
+    Ok(documents)
+}
+
+/// Adds every client and active product to the matrix, so they are indexed by the
+/// model even without interactions
+async fn add_catalog(
+    client: &mut Client<Compat<TcpStream>>,
+    matrix: &mut ClientProductMatrix,
+    query_clients: String,
+    query_products: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    {
+        let mut result_clients = client.query(query_clients, &[]).await?;
+        while let Some(item) = result_clients.try_next().await? {
+            if let Some(row) = item.into_row() {
+                let client_id: String = row
+                    .get::<&str, _>(0)
+                    .unwrap_or("unknown_client")
+                    .to_string();
+                matrix.entry(client_id).or_insert_with(HashMap::new);
+            }
+        }
+    }
+
+    {
+        let mut result_products = client.query(query_products, &[]).await?;
+        while let Some(item) = result_products.try_next().await? {
+            if let Some(row) = item.into_row() {
+                let product_id: String = row
+                    .get::<&str, _>(0)
+                    .unwrap_or("unknown_product")
+                    .to_string();
+                if let Some(client_products) = matrix.values_mut().next() {
+                    client_products.entry(product_id.clone()).or_insert(0.0);
+                }
+            }
+        }
+    }
+    Ok(())
 }
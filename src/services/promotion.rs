@@ -0,0 +1,185 @@
+use crate::models::db::ClientProductMatrix;
+use crate::services::als::ALS;
+use crate::services::modelfile::{load_model, ModelMetadata};
+use crate::services::training::JSONData;
+use serde::Deserialize;
+use std::path::Path;
+use tracing::info;
+
+const DEFAULT_MAX_EPR_INCREASE: f64 = 0.01;
+const DEFAULT_MAX_COUNT_DROP: f64 = 0.2;
+/// Fewer held out interactions than this make the EPRs too noisy to compare
+const MIN_HOLDOUT: usize = 50;
+
+/// Decides whether a newly trained model may replace the one being served, so a run
+/// on partially extracted data or a worse model doesn't reach production
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PromotionGate {
+    /// When off, every model is published
+    pub enabled: bool,
+    /// Largest EPR increase (worse ranking) accepted, in absolute terms
+    pub max_epr_increase: f64,
+    /// Largest relative drop in clients, products or interactions accepted
+    pub max_count_drop: f64,
+}
+
+impl Default for PromotionGate {
+    fn default() -> Self {
+        PromotionGate {
+            enabled: true,
+            max_epr_increase: DEFAULT_MAX_EPR_INCREASE,
+            max_count_drop: DEFAULT_MAX_COUNT_DROP,
+        }
+    }
+}
+
+impl PromotionGate {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_epr_increase < 0.0 {
+            return Err("gate max_epr_increase can't be negative".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.max_count_drop) {
+            return Err("gate max_count_drop must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Interactions of `matrix` the model served from `model_file` has never seen:
+    /// purchases of clients and products it knows, made since it was trained. The
+    /// candidate is trained without them, so both models are scored on data neither
+    /// was fit on. `None` when the gate is off, nothing is served or too few are new.
+    pub fn holdout(
+        &self,
+        matrix: &ClientProductMatrix,
+        model_file: &str,
+    ) -> Option<ClientProductMatrix> {
+        if !self.enabled || !Path::new(model_file).exists() {
+            return None;
+        }
+        let (current, _) = load_model(model_file).ok()?;
+        let mut holdout = ClientProductMatrix::new();
+        let mut count = 0;
+        for (client_id, products) in matrix {
+            if !current.client_index.contains_key(client_id) {
+                continue;
+            }
+            let seen = current.matrix.get(client_id);
+            for (product_id, &quantity) in products {
+                let is_new = quantity > 0.0
+                    && current.product_index.contains_key(product_id)
+                    && !seen
+                        .and_then(|seen| seen.get(product_id))
+                        .is_some_and(|&before| before > 0.0);
+                if is_new {
+                    holdout
+                        .entry(client_id.clone())
+                        .or_default()
+                        .insert(product_id.clone(), quantity);
+                    count += 1;
+                }
+            }
+        }
+        if count < MIN_HOLDOUT {
+            info!(
+                "Only {} interactions since the served model, not holding any out",
+                count
+            );
+            return None;
+        }
+        info!(
+            "Holding out {} interactions since the served model for validation",
+            count
+        );
+        Some(holdout)
+    }
+
+    /// Compares `candidate` with the model served from `model_file`: counts against
+    /// its metadata and, with a `holdout`, the EPR of both models on those held out
+    /// interactions. Returns the reason when the candidate is refused.
+    pub fn check(
+        &self,
+        candidate_data: &JSONData,
+        candidate: &ModelMetadata,
+        holdout: Option<&ClientProductMatrix>,
+        model_file: &str,
+    ) -> Result<(), String> {
+        if !self.enabled || !Path::new(model_file).exists() {
+            return Ok(());
+        }
+        let (current_data, current) = match load_model(model_file) {
+            Ok(current) => current,
+            Err(e) => {
+                info!(
+                    "Served model can't be loaded ({}), skipping the comparison",
+                    e
+                );
+                return Ok(());
+            }
+        };
+
+        for (what, before, after) in [
+            ("clients", current.num_clients, candidate.num_clients),
+            ("products", current.num_products, candidate.num_products),
+            (
+                "interactions",
+                current.num_interactions,
+                candidate.num_interactions,
+            ),
+        ] {
+            if before > 0 && (after as f64) < before as f64 * (1.0 - self.max_count_drop) {
+                return Err(format!(
+                    "number of {} dropped from {} to {} ({:.1}%, more than {:.1}%)",
+                    what,
+                    before,
+                    after,
+                    (1.0 - after as f64 / before as f64) * 100.0,
+                    self.max_count_drop * 100.0
+                ));
+            }
+        }
+
+        let Some(holdout) = holdout else {
+            return Ok(());
+        };
+        let (Some(candidate_epr), Some(current_epr)) = (
+            evaluate(candidate_data, holdout),
+            evaluate(&current_data, holdout),
+        ) else {
+            return Ok(());
+        };
+        info!(
+            "Held out EPR of the candidate: {:.2}%, of the served model: {:.2}%",
+            candidate_epr * 100.0,
+            current_epr * 100.0
+        );
+        if candidate_epr > current_epr + self.max_epr_increase {
+            return Err(format!(
+                "EPR {:.2}% is worse than the served model's {:.2}% by more than {:.2} points",
+                candidate_epr * 100.0,
+                current_epr * 100.0,
+                self.max_epr_increase * 100.0
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// EPR of a model on `matrix`; clients and products it doesn't know are skipped
+pub fn evaluate(data: &JSONData, matrix: &ClientProductMatrix) -> Option<f64> {
+    let mut model = ALS::new(
+        data.hyperparameters.num_factors,
+        data.hyperparameters.regularization,
+        data.hyperparameters.confidence_multiplier,
+        1e-4,
+        0,
+        matrix.clone(),
+    );
+    model.build_from_data(
+        &data.client_factors,
+        &data.product_factors,
+        &data.client_index,
+        &data.product_index,
+    );
+    model.compute_epr()
+}
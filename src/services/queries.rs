@@ -0,0 +1,455 @@
+use crate::models::db::Watermark;
+use crate::models::schema::{InvoiceLineTable, InvoiceTable, SchemaMapping};
+
+const PAGE_SIZE: i64 = 10;
+/// Longest `IN (...)` list in a query; Firebird refuses more than 1500 values
+pub const MAX_IN_LIST: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    SqlServer,
+    Firebird,
+}
+
+/// Builds the extraction and catalog queries from a `SchemaMapping`, so both SQL
+/// backends share the same SQL and only differ in dialect details.
+pub struct QueryBuilder {
+    schema: SchemaMapping,
+    dialect: Dialect,
+}
+
+impl QueryBuilder {
+    pub fn new(schema: SchemaMapping, dialect: Dialect) -> Self {
+        QueryBuilder { schema, dialect }
+    }
+
+    fn table(&self, name: &str) -> String {
+        match (self.dialect, &self.schema.schema) {
+            (Dialect::SqlServer, Some(schema)) => format!("{}.{}", schema, name),
+            _ => name.to_string(),
+        }
+    }
+
+    fn paginate(&self, order_by: &str, page: i64) -> String {
+        match self.dialect {
+            Dialect::SqlServer => format!(
+                "ORDER BY {} OFFSET {} ROWS FETCH NEXT {} ROWS ONLY",
+                order_by,
+                (page - 1) * PAGE_SIZE,
+                PAGE_SIZE
+            ),
+            Dialect::Firebird => format!(
+                "ORDER BY {} ROWS {} TO {}",
+                order_by,
+                (page - 1) * PAGE_SIZE + 1,
+                page * PAGE_SIZE
+            ),
+        }
+    }
+
+    /// Conditions keeping only clients that are not excluded, on the clients table
+    /// aliased as `alias`
+    fn client_filters(&self, alias: &str) -> Vec<String> {
+        let clients = &self.schema.clients;
+        let mut filters: Vec<String> = clients
+            .excluded_name_patterns
+            .iter()
+            .map(|pattern| format!("{}.{} NOT LIKE {}", alias, clients.name, quote(pattern)))
+            .collect();
+        if !clients.excluded_ids.is_empty() {
+            filters.push(format!(
+                "{}.{} NOT IN ({})",
+                alias,
+                clients.id,
+                quote_list(&clients.excluded_ids)
+            ));
+        }
+        filters
+    }
+
+    /// Conditions keeping only active products, on the products table aliased as `alias`
+    fn product_filters(&self, alias: &str) -> Vec<String> {
+        let products = &self.schema.products;
+        match &products.status {
+            Some(status) if !products.active_statuses.is_empty() => vec![format!(
+                "{}.{} IN ({})",
+                alias,
+                status,
+                quote_list(&products.active_statuses)
+            )],
+            _ => Vec::new(),
+        }
+    }
+
+    fn invoice_filters(&self, alias: &str) -> Vec<String> {
+        status_filters(&self.schema.invoices, alias)
+    }
+
+    /// Total quantity returned per document, client and product for each kind of
+    /// return document, optionally only those dated on or after `since` (a date).
+    /// Each query is paired with its header table, which together with the document
+    /// identifies a return. Empty when return subtraction is disabled.
+    pub fn returned_interactions(&self, since: Option<&str>) -> Vec<(String, String)> {
+        let returns = &self.schema.returns;
+        if !returns.enabled {
+            return Vec::new();
+        }
+        returns
+            .documents
+            .iter()
+            .map(|documents| {
+                let mut filters = status_filters(&documents.headers, "F");
+                if let (Some(date), Some(since)) = (&documents.headers.date, since) {
+                    filters.push(format!("F.{} >= {}", date, quote(since)));
+                }
+                filters.extend(self.client_filters("C"));
+                filters.extend(self.product_filters("I"));
+                (
+                    documents.headers.table.clone(),
+                    self.documents_query(&documents.headers, &documents.lines, filters, true),
+                )
+            })
+            .collect()
+    }
+
+    /// Total quantity bought per client and product
+    pub fn interactions(&self) -> String {
+        let mut filters = self.invoice_filters("F");
+        filters.extend(self.client_filters("C"));
+        filters.extend(self.product_filters("I"));
+        self.interactions_query(filters, false)
+    }
+
+    /// Total quantity bought per client and product on invoices after `since` (or
+    /// from the beginning) up to `until`. `None` when the mapping has no invoice date.
+    pub fn interactions_between(
+        &self,
+        since: Option<&Watermark>,
+        until: &Watermark,
+    ) -> Option<String> {
+        let mut filters = self.invoice_filters("F");
+        filters.extend(self.client_filters("C"));
+        filters.extend(self.product_filters("I"));
+        if let Some(since) = since {
+            filters.push(self.after("F", since)?);
+        }
+        filters.push(format!("NOT {}", self.after("F", until)?));
+        Some(self.interactions_query(filters, false))
+    }
+
+    /// Quantities per document, client and product of invoices counted up to `since`
+    /// that have been cancelled since, optionally only those cancelled on or after
+    /// `cancelled_since` (a date). `None` when the mapping can't tell cancelled invoices.
+    pub fn cancelled_interactions(
+        &self,
+        since: &Watermark,
+        cancelled_since: Option<&str>,
+    ) -> Option<String> {
+        let invoices = &self.schema.invoices;
+        let status = invoices.status.as_ref()?;
+        if invoices.excluded_statuses.is_empty() {
+            return None;
+        }
+        let mut filters = vec![
+            format!(
+                "F.{} IN ({})",
+                status,
+                quote_list(&invoices.excluded_statuses)
+            ),
+            format!("NOT {}", self.after("F", since)?),
+        ];
+        if let (Some(cancel_date), Some(cancelled_since)) = (&invoices.cancel_date, cancelled_since)
+        {
+            filters.push(format!("F.{} >= {}", cancel_date, quote(cancelled_since)));
+        }
+        filters.extend(self.client_filters("C"));
+        filters.extend(self.product_filters("I"));
+        Some(self.interactions_query(filters, true))
+    }
+
+    /// Date and document of the latest invoice. `None` when the mapping has no
+    /// invoice date.
+    pub fn watermark(&self) -> Option<String> {
+        let invoices = &self.schema.invoices;
+        let date = invoices.date.as_ref()?;
+        Some(match self.dialect {
+            Dialect::SqlServer => format!(
+                "SELECT TOP 1 CONVERT(VARCHAR(23), F.{date}, 121) AS LAST_DATE, F.{doc} AS LAST_DOC
+                 FROM {table} AS F
+                 ORDER BY F.{date} DESC, F.{doc} DESC;",
+                date = date,
+                doc = invoices.document,
+                table = self.table(&invoices.table),
+            ),
+            Dialect::Firebird => format!(
+                "SELECT FIRST 1 CAST(F.{date} AS VARCHAR(24)) AS LAST_DATE, F.{doc} AS LAST_DOC
+                 FROM {table} AS F
+                 ORDER BY F.{date} DESC, F.{doc} DESC;",
+                date = date,
+                doc = invoices.document,
+                table = self.table(&invoices.table),
+            ),
+        })
+    }
+
+    /// Condition matching invoices after `watermark`, ordered by date then document
+    fn after(&self, alias: &str, watermark: &Watermark) -> Option<String> {
+        let invoices = &self.schema.invoices;
+        let date = invoices.date.as_ref()?;
+        Some(format!(
+            "({alias}.{date} > {last_date} OR ({alias}.{date} = {last_date} AND {alias}.{doc} > {last_doc}))",
+            alias = alias,
+            date = date,
+            doc = invoices.document,
+            last_date = quote(&watermark.date),
+            last_doc = quote(&watermark.document),
+        ))
+    }
+
+    fn interactions_query(&self, filters: Vec<String>, by_document: bool) -> String {
+        let s = &self.schema;
+        self.documents_query(&s.invoices, &s.invoice_lines, filters, by_document)
+    }
+
+    /// Quantity per client and product (and document when `by_document`) on the lines
+    /// of `headers` aliased as `F`, joined to lines `PF`, clients `C` and products `I`
+    fn documents_query(
+        &self,
+        headers: &InvoiceTable,
+        lines: &InvoiceLineTable,
+        filters: Vec<String>,
+        by_document: bool,
+    ) -> String {
+        let s = &self.schema;
+        let document = if by_document {
+            format!("F.{}", headers.document)
+        } else {
+            String::new()
+        };
+        format!(
+            "SELECT {select_doc}F.{client} AS CLIENT_ID, PF.{product} AS PRODUCT_ID, SUM(PF.{quantity}) AS TOTAL_QUANTITY
+             FROM {lines} AS PF
+             INNER JOIN {invoices} AS F ON PF.{line_doc} = F.{invoice_doc}
+             INNER JOIN {clients} AS C ON C.{client_id} = F.{client}
+             INNER JOIN {products} AS I ON PF.{product} = I.{product_id}
+             {filters}
+             GROUP BY {group_doc}F.{client}, PF.{product};",
+            select_doc = if by_document { format!("{} AS DOCUMENT, ", document) } else { String::new() },
+            group_doc = if by_document { format!("{}, ", document) } else { String::new() },
+            client = headers.client,
+            product = lines.product,
+            quantity = lines.quantity,
+            lines = self.table(&lines.table),
+            invoices = self.table(&headers.table),
+            line_doc = lines.document,
+            invoice_doc = headers.document,
+            clients = self.table(&s.clients.table),
+            client_id = s.clients.id,
+            products = self.table(&s.products.table),
+            product_id = s.products.id,
+            filters = where_clause(filters),
+        )
+    }
+
+    pub fn all_clients(&self) -> String {
+        format!(
+            "SELECT C.{} AS CLIENT_ID FROM {} AS C;",
+            self.schema.clients.id,
+            self.table(&self.schema.clients.table)
+        )
+    }
+
+    pub fn active_products(&self) -> String {
+        format!(
+            "SELECT I.{} AS PRODUCT_ID FROM {} AS I {};",
+            self.schema.products.id,
+            self.table(&self.schema.products.table),
+            where_clause(self.product_filters("I"))
+        )
+    }
+
+    /// Every client the search can return, for the in-memory catalog
+    pub fn client_catalog(&self) -> String {
+        let clients = &self.schema.clients;
+        format!(
+            "SELECT C.{} AS id, C.{} AS name, C.{} AS email
+             FROM {} AS C
+             {};",
+            clients.id,
+            clients.name,
+            clients.email,
+            self.table(&clients.table),
+            where_clause(self.client_filters("C"))
+        )
+    }
+
+    /// Every product the search can return, for the in-memory catalog
+    pub fn product_catalog(&self) -> String {
+        let products = &self.schema.products;
+        format!(
+            "SELECT I.{} AS id, I.{} AS description, I.{} AS price
+             FROM {} AS I
+             {};",
+            products.id,
+            products.description,
+            products.price,
+            self.table(&products.table),
+            where_clause(self.product_filters("I"))
+        )
+    }
+
+    fn clients_search_filters(&self, search: &str) -> Vec<String> {
+        let mut filters = vec![format!(
+            "C.{} LIKE {}",
+            self.schema.clients.name,
+            quote(&format!("%{}%", search))
+        )];
+        filters.extend(self.client_filters("C"));
+        filters
+    }
+
+    pub fn clients_page(&self, search: &str, page: i64) -> String {
+        let clients = &self.schema.clients;
+        format!(
+            "SELECT C.{} AS id, C.{} AS name, C.{} AS email
+             FROM {} AS C
+             {}
+             {};",
+            clients.id,
+            clients.name,
+            clients.email,
+            self.table(&clients.table),
+            where_clause(self.clients_search_filters(search)),
+            self.paginate(&format!("C.{}", clients.id), page)
+        )
+    }
+
+    pub fn clients_total_pages(&self, search: &str) -> String {
+        format!(
+            "SELECT (COUNT(*)-1)/{}+1 AS total_pages
+             FROM {} AS C
+             {};",
+            PAGE_SIZE,
+            self.table(&self.schema.clients.table),
+            where_clause(self.clients_search_filters(search))
+        )
+    }
+
+    fn products_search_filters(&self, search: &str) -> Vec<String> {
+        let mut filters = vec![format!(
+            "I.{} LIKE {}",
+            self.schema.products.description,
+            quote(&format!("%{}%", search))
+        )];
+        filters.extend(self.product_filters("I"));
+        filters
+    }
+
+    pub fn products_page(&self, search: &str, page: i64) -> String {
+        let products = &self.schema.products;
+        format!(
+            "SELECT I.{} AS id, I.{} AS description, I.{} AS price
+             FROM {} AS I
+             {}
+             {};",
+            products.id,
+            products.description,
+            products.price,
+            self.table(&products.table),
+            where_clause(self.products_search_filters(search)),
+            self.paginate(&format!("I.{}", products.id), page)
+        )
+    }
+
+    pub fn products_total_pages(&self, search: &str) -> String {
+        format!(
+            "SELECT (COUNT(*)-1)/{}+1 AS total_pages
+             FROM {} AS I
+             {};",
+            PAGE_SIZE,
+            self.table(&self.schema.products.table),
+            where_clause(self.products_search_filters(search))
+        )
+    }
+
+    pub fn client_by_id(&self, id: &str) -> String {
+        let clients = &self.schema.clients;
+        format!(
+            "SELECT C.{} AS id, C.{} AS name, C.{} AS email
+             FROM {} AS C
+             WHERE C.{} = {};",
+            clients.id,
+            clients.name,
+            clients.email,
+            self.table(&clients.table),
+            clients.id,
+            quote(id)
+        )
+    }
+
+    pub fn product_by_id(&self, id: &str) -> String {
+        let products = &self.schema.products;
+        format!(
+            "SELECT I.{} AS id, I.{} AS description, I.{} AS price
+             FROM {} AS I
+             WHERE I.{} = {};",
+            products.id,
+            products.description,
+            products.price,
+            self.table(&products.table),
+            products.id,
+            quote(id)
+        )
+    }
+
+    /// Products among `ids`; callers split longer lists in chunks of `MAX_IN_LIST`
+    pub fn products_by_ids(&self, ids: &[String]) -> String {
+        let products = &self.schema.products;
+        format!(
+            "SELECT I.{} AS id, I.{} AS description, I.{} AS price
+             FROM {} AS I
+             WHERE I.{} IN ({});",
+            products.id,
+            products.description,
+            products.price,
+            self.table(&products.table),
+            products.id,
+            quote_list(ids)
+        )
+    }
+}
+
+/// Conditions leaving out documents with an excluded status (e.g. cancelled), on the
+/// header table aliased as `alias`
+fn status_filters(headers: &InvoiceTable, alias: &str) -> Vec<String> {
+    match &headers.status {
+        Some(status) if !headers.excluded_statuses.is_empty() => vec![format!(
+            "{}.{} NOT IN ({})",
+            alias,
+            status,
+            quote_list(&headers.excluded_statuses)
+        )],
+        _ => Vec::new(),
+    }
+}
+
+fn where_clause(filters: Vec<String>) -> String {
+    if filters.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", filters.join(" AND "))
+    }
+}
+
+/// Quotes a value as an SQL string literal
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn quote_list(values: &[String]) -> String {
+    values
+        .iter()
+        .map(|value| quote(value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,221 @@
+use crate::error::AppError;
+use crate::services::modelfile::{
+    is_json, load_model, save_model, write_atomically, ModelMetadata,
+};
+use crate::services::training::JSONData;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const INDEX_FILE: &str = "registry.json";
+
+lazy_static::lazy_static! {
+    /// Serializes changes to the registries, which may come from the training jobs and
+    /// the API at the same time
+    static ref REGISTRY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A model kept in the registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelVersion {
+    pub version: u64,
+    pub file: String,
+    pub metadata: ModelMetadata,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RegistryIndex {
+    active: Option<u64>,
+    /// Oldest first
+    versions: Vec<ModelVersion>,
+}
+
+/// Listing of the registry returned by the API and CLI
+#[derive(Debug, Serialize)]
+pub struct ModelVersions {
+    pub active: Option<u64>,
+    pub versions: Vec<ModelVersion>,
+}
+
+/// Keeps the last trained models of a tenant in `dir`, so a bad model can be rolled
+/// back. The active version is copied over `model_file`, which is the file the model
+/// server loads and watches.
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    dir: PathBuf,
+    model_file: String,
+    keep: usize,
+}
+
+impl ModelRegistry {
+    /// Registry stored in `dir` and serving through `model_file`, keeping the last
+    /// `keep` models
+    pub fn new(dir: impl Into<PathBuf>, model_file: &str, keep: usize) -> Self {
+        ModelRegistry {
+            dir: dir.into(),
+            model_file: model_file.to_string(),
+            keep: keep.max(1),
+        }
+    }
+
+    pub fn model_file(&self) -> &str {
+        &self.model_file
+    }
+
+    /// Stores a new model as the next version, makes it the active one and drops the
+    /// oldest versions beyond the ones to keep
+    pub fn publish(
+        &self,
+        data: &JSONData,
+        metadata: ModelMetadata,
+    ) -> Result<ModelVersion, AppError> {
+        if is_json(&self.model_file) {
+            return Err(AppError::Internal(format!(
+                "Can't publish to {}: JSON model files have no checksum",
+                self.model_file
+            )));
+        }
+        let _lock = REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut index = self.read_index().map_err(internal)?;
+
+        let version = index.versions.last().map_or(1, |last| last.version + 1);
+        let file = self.version_file(version);
+        let metadata = ModelMetadata {
+            version: Some(version),
+            ..metadata
+        };
+        save_model(data, &metadata, &self.dir.join(&file).to_string_lossy()).map_err(internal)?;
+
+        let entry = ModelVersion {
+            version,
+            file,
+            metadata,
+        };
+        index.versions.push(entry.clone());
+        self.activate_entry(&mut index, version)?;
+        self.prune(&mut index);
+        self.write_index(&index).map_err(internal)?;
+        info!("Published model version {}", version);
+        Ok(entry)
+    }
+
+    pub fn list(&self) -> Result<ModelVersions, AppError> {
+        let index = self.read_index().map_err(internal)?;
+        Ok(ModelVersions {
+            active: index.active,
+            versions: index.versions,
+        })
+    }
+
+    /// Serves `version` again
+    pub fn activate(&self, version: u64) -> Result<ModelVersion, AppError> {
+        let _lock = REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut index = self.read_index().map_err(internal)?;
+        let entry = self.activate_entry(&mut index, version)?;
+        self.write_index(&index).map_err(internal)?;
+        info!("Activated model version {}", version);
+        Ok(entry)
+    }
+
+    /// Serves the newest version older than the active one
+    pub fn rollback(&self) -> Result<ModelVersion, AppError> {
+        let _lock = REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut index = self.read_index().map_err(internal)?;
+        let active = index
+            .active
+            .ok_or_else(|| AppError::NotFound("active model version".to_string()))?;
+        let previous = index
+            .versions
+            .iter()
+            .rev()
+            .map(|entry| entry.version)
+            .find(|&version| version < active)
+            .ok_or_else(|| {
+                AppError::NotFound(format!(
+                    "model version older than {} to roll back to",
+                    active
+                ))
+            })?;
+        let entry = self.activate_entry(&mut index, previous)?;
+        self.write_index(&index).map_err(internal)?;
+        info!("Rolled back from model version {} to {}", active, previous);
+        Ok(entry)
+    }
+
+    /// Validates the version's file and copies it atomically over the served model
+    fn activate_entry(
+        &self,
+        index: &mut RegistryIndex,
+        version: u64,
+    ) -> Result<ModelVersion, AppError> {
+        let entry = index
+            .versions
+            .iter()
+            .find(|entry| entry.version == version)
+            .cloned()
+            .ok_or_else(|| AppError::NotFound(format!("model version {}", version)))?;
+        let path = self.dir.join(&entry.file);
+        load_model(&path.to_string_lossy()).map_err(|e| {
+            AppError::Internal(format!("model version {} is not valid: {}", version, e))
+        })?;
+
+        write_atomically(Path::new(&self.model_file), |writer| {
+            io::copy(&mut File::open(&path)?, writer)?;
+            Ok(())
+        })
+        .map_err(internal)?;
+        index.active = Some(version);
+        Ok(entry)
+    }
+
+    fn prune(&self, index: &mut RegistryIndex) {
+        while index.versions.len() > self.keep {
+            let Some(position) = index
+                .versions
+                .iter()
+                .position(|entry| Some(entry.version) != index.active)
+            else {
+                break;
+            };
+            let entry = index.versions.remove(position);
+            if let Err(e) = fs::remove_file(self.dir.join(&entry.file)) {
+                warn!("Failed to remove model version {}: {}", entry.version, e);
+            }
+        }
+    }
+
+    /// Versions are stored with the extension of the served model file, so they keep
+    /// its format
+    fn version_file(&self, version: u64) -> String {
+        match Path::new(&self.model_file).extension() {
+            Some(extension) => format!("model-{:06}.{}", version, extension.to_string_lossy()),
+            None => format!("model-{:06}", version),
+        }
+    }
+
+    fn read_index(&self) -> Result<RegistryIndex, Error> {
+        match fs::read(self.dir.join(INDEX_FILE)) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(RegistryIndex::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_index(&self, index: &RegistryIndex) -> Result<(), Error> {
+        write_atomically(&self.dir.join(INDEX_FILE), |writer| {
+            serde_json::to_writer_pretty(writer, index)?;
+            Ok(())
+        })
+    }
+}
+
+/// Reading or writing the registry failed, which is never the caller's fault
+fn internal(error: impl Display) -> AppError {
+    AppError::Internal(error.to_string())
+}
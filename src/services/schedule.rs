@@ -0,0 +1,193 @@
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Weekday};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+/// What a scheduled job trains
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobMode {
+    /// Full hyperparameter search
+    #[default]
+    Search,
+    /// Retrains the served model for a few iterations, starting from its factors
+    Refit,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrainingJob {
+    pub name: String,
+    /// Cron expression with seconds, e.g. `0 0 2 * * Sun`
+    pub cron: String,
+    #[serde(default)]
+    pub mode: JobMode,
+}
+
+/// Time of day when scheduled jobs don't start, e.g. business hours. A window whose
+/// end is before its start runs past midnight.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Blackout {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Days the window applies to, every day when empty; for windows past midnight,
+    /// the day they start
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+impl Blackout {
+    fn contains<T: TimeZone>(&self, now: &DateTime<T>) -> bool {
+        let time = now.time();
+        let applies = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.start <= self.end {
+            applies(now.weekday()) && time >= self.start && time < self.end
+        } else {
+            (applies(now.weekday()) && time >= self.start)
+                || (applies(now.weekday().pred()) && time < self.end)
+        }
+    }
+}
+
+/// When the training jobs of a tenant run
+#[derive(Debug, Clone)]
+pub struct TrainingSchedule {
+    pub timezone: Tz,
+    pub jobs: Vec<TrainingJob>,
+    pub blackouts: Vec<Blackout>,
+}
+
+impl TrainingSchedule {
+    /// A single full search on `cron`
+    pub fn single(cron: &str, timezone: Tz) -> Self {
+        TrainingSchedule {
+            timezone,
+            jobs: vec![TrainingJob {
+                name: "training".to_string(),
+                cron: cron.to_string(),
+                mode: JobMode::Search,
+            }],
+            blackouts: Vec::new(),
+        }
+    }
+
+    /// The blackout window `now` falls in, if any
+    pub fn blackout_at<T: TimeZone>(&self, now: &DateTime<T>) -> Option<&Blackout> {
+        let now = now.with_timezone(&self.timezone);
+        self.blackouts
+            .iter()
+            .find(|blackout| blackout.contains(&now))
+    }
+}
+
+/// `schedule` in the tenants file: either a cron expression for a single full search
+/// or a table with the timezone, jobs and blackout windows
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ScheduleEntry {
+    Cron(String),
+    Table(ScheduleTable),
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ScheduleTable {
+    timezone: Option<String>,
+    #[serde(default, rename = "job")]
+    jobs: Vec<TrainingJob>,
+    #[serde(default, rename = "blackout")]
+    blackouts: Vec<Blackout>,
+}
+
+impl ScheduleEntry {
+    /// The schedule, in `timezone` unless it names its own
+    pub(crate) fn into_schedule(self, timezone: Tz) -> Result<TrainingSchedule, String> {
+        match self {
+            ScheduleEntry::Cron(cron) => Ok(TrainingSchedule::single(&cron, timezone)),
+            ScheduleEntry::Table(table) if table.jobs.is_empty() => {
+                Err("at least one [[schedule.job]] is required".to_string())
+            }
+            ScheduleEntry::Table(table) => Ok(TrainingSchedule {
+                timezone: match table.timezone {
+                    Some(timezone) => parse_timezone(&timezone)?,
+                    None => timezone,
+                },
+                jobs: table.jobs,
+                blackouts: table.blackouts,
+            }),
+        }
+    }
+}
+
+pub(crate) fn parse_timezone(timezone: &str) -> Result<Tz, String> {
+    timezone
+        .parse()
+        .map_err(|e| format!("invalid timezone {}: {}", timezone, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, Utc};
+
+    fn blackout(start: &str, end: &str, days: Vec<Weekday>) -> Blackout {
+        Blackout {
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            days,
+        }
+    }
+
+    /// 2024-01-01 was a Monday
+    fn at(day: u32, time: &str) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(time.parse().unwrap())
+            .and_utc()
+    }
+
+    #[test]
+    fn contains_window_within_a_day() {
+        let window = blackout("08:00:00", "18:00:00", Vec::new());
+        assert!(window.contains(&at(1, "08:00:00")));
+        assert!(window.contains(&at(1, "17:59:59")));
+        assert!(!window.contains(&at(1, "18:00:00")));
+        assert!(!window.contains(&at(1, "07:59:59")));
+    }
+
+    #[test]
+    fn contains_window_past_midnight() {
+        let window = blackout("22:00:00", "06:00:00", Vec::new());
+        assert!(window.contains(&at(1, "22:00:00")));
+        assert!(window.contains(&at(1, "23:30:00")));
+        assert!(window.contains(&at(2, "05:59:59")));
+        assert!(!window.contains(&at(2, "06:00:00")));
+        assert!(!window.contains(&at(1, "12:00:00")));
+    }
+
+    #[test]
+    fn contains_only_on_listed_days() {
+        let window = blackout("08:00:00", "18:00:00", vec![Weekday::Mon, Weekday::Tue]);
+        assert!(window.contains(&at(1, "09:00:00")));
+        assert!(window.contains(&at(2, "09:00:00")));
+        assert!(!window.contains(&at(3, "09:00:00")));
+        assert!(!window.contains(&at(7, "09:00:00")));
+    }
+
+    #[test]
+    fn contains_past_midnight_by_start_day() {
+        // Friday night into Saturday morning only
+        let window = blackout("22:00:00", "06:00:00", vec![Weekday::Fri]);
+        assert!(window.contains(&at(5, "23:00:00")));
+        assert!(window.contains(&at(6, "05:00:00")));
+        assert!(!window.contains(&at(6, "23:00:00")));
+        assert!(!window.contains(&at(5, "05:00:00")));
+    }
+
+    #[test]
+    fn schedule_without_jobs_is_rejected() {
+        let entry = ScheduleEntry::Table(ScheduleTable {
+            timezone: None,
+            jobs: Vec::new(),
+            blackouts: Vec::new(),
+        });
+        assert!(entry.into_schedule(Tz::UTC).is_err());
+    }
+}
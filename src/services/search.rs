@@ -0,0 +1,221 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use strsim::osa_distance;
+
+/// Score of a query word found as a whole word, as the start of one or with typos
+const EXACT: u32 = 3;
+const PREFIX: u32 = 2;
+const TYPO: u32 = 1;
+/// Added when the text contains the whole query, as `LIKE '%query%'` matched
+const PHRASE: u32 = 2;
+
+/// Lowercase `text` without accents, so "JABÓN" and "jabon" compare equal
+pub fn fold(text: &str) -> String {
+    text.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'á' | 'à' | 'â' | 'ä' | 'ã' | 'å' => 'a',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'í' | 'ì' | 'î' | 'ï' => 'i',
+            'ó' | 'ò' | 'ô' | 'ö' | 'õ' => 'o',
+            'ú' | 'ù' | 'û' | 'ü' => 'u',
+            'ý' | 'ÿ' => 'y',
+            'ñ' => 'n',
+            'ç' => 'c',
+            c => c,
+        })
+        .collect()
+}
+
+/// Folded words of `text`, split on anything but letters and digits
+pub fn tokenize(text: &str) -> Vec<String> {
+    fold(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Typos allowed in a query word; none in short words, which would match almost
+/// anything
+fn max_typos(term: &str) -> usize {
+    match term.chars().count() {
+        0..=4 => 0,
+        5..=8 => 1,
+        _ => 2,
+    }
+}
+
+/// Whether `word`, or its start for a query typed halfway, is at most `max_typos`
+/// edits from `term`. The edit distance is at least the difference in length, which
+/// skips most words without computing it.
+fn within_typos(term: &str, word: &str, max_typos: usize) -> bool {
+    let length = term.chars().count();
+    let word_length = word.chars().count();
+    if word_length + max_typos < length {
+        return false;
+    }
+    let start: String = word.chars().take(length).collect();
+    osa_distance(term, &start) <= max_typos
+        || (word_length <= length + max_typos && osa_distance(term, word) <= max_typos)
+}
+
+/// Word index of a list of texts, e.g. the client names of the catalog
+pub struct SearchIndex {
+    /// Folded text of each document, for the phrase matches
+    texts: Vec<String>,
+    /// Documents containing each word, sorted so the words with a common start are
+    /// next to each other
+    words: BTreeMap<String, Vec<usize>>,
+}
+
+impl SearchIndex {
+    pub fn new<'a>(texts: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut index = SearchIndex {
+            texts: Vec::new(),
+            words: BTreeMap::new(),
+        };
+        for (doc, text) in texts.into_iter().enumerate() {
+            for word in tokenize(text) {
+                let docs = index.words.entry(word).or_default();
+                if docs.last() != Some(&doc) {
+                    docs.push(doc);
+                }
+            }
+            index.texts.push(fold(text));
+        }
+        index
+    }
+
+    /// Documents matching `query`, best first and then in index order; all of them for
+    /// an empty query. A document matches when every word of the query is one of its
+    /// words, the start of one or close to one with the same first letter, or when it
+    /// contains the whole query.
+    pub fn search(&self, query: &str) -> Vec<usize> {
+        let terms = tokenize(query);
+        if terms.is_empty() {
+            return (0..self.texts.len()).collect();
+        }
+        let mut scores = self.term_matches(&terms[0]);
+        for term in &terms[1..] {
+            let matches = self.term_matches(term);
+            scores = scores
+                .into_iter()
+                .filter_map(|(doc, score)| matches.get(&doc).map(|other| (doc, score + other)))
+                .collect();
+        }
+
+        let phrase = fold(query.trim());
+        for (doc, text) in self.texts.iter().enumerate() {
+            if text.contains(&phrase) {
+                *scores.entry(doc).or_default() += PHRASE;
+            }
+        }
+
+        let mut ranked: Vec<(usize, u32)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked.into_iter().map(|(doc, _)| doc).collect()
+    }
+
+    /// Best score of `term` in each document it matches
+    fn term_matches(&self, term: &str) -> HashMap<usize, u32> {
+        let mut matches = HashMap::new();
+        let from = (Bound::Included(term), Bound::Unbounded);
+        for (word, docs) in self
+            .words
+            .range::<str, _>(from)
+            .take_while(|(word, _)| word.starts_with(term))
+        {
+            let score = if word == term { EXACT } else { PREFIX };
+            add_matches(&mut matches, docs, score);
+        }
+
+        // Only words with the same first letter, which are next to each other in the
+        // index: a typo there is rare and checking every word is too slow
+        let max_typos = max_typos(term);
+        if let Some(first) = term.chars().next().filter(|_| max_typos > 0) {
+            let first = first.to_string();
+            let from = (Bound::Included(first.as_str()), Bound::Unbounded);
+            for (word, docs) in self
+                .words
+                .range::<str, _>(from)
+                .take_while(|(word, _)| word.starts_with(&first))
+            {
+                if !word.starts_with(term) && within_typos(term, word, max_typos) {
+                    add_matches(&mut matches, docs, TYPO);
+                }
+            }
+        }
+        matches
+    }
+}
+
+fn add_matches(matches: &mut HashMap<usize, u32>, docs: &[usize], score: u32) {
+    for &doc in docs {
+        let best = matches.entry(doc).or_insert(score);
+        *best = (*best).max(score);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> SearchIndex {
+        SearchIndex::new([
+            "JABÓN DE TOCADOR",
+            "Jabonera de baño",
+            "Detergente en polvo",
+            "Papel higiénico",
+            "Cepillo dental",
+        ])
+    }
+
+    #[test]
+    fn fold_lowercases_and_drops_accents() {
+        assert_eq!(fold("JABÓN"), "jabon");
+        assert_eq!(fold("Pañal Ñandú"), "panal nandu");
+        assert_eq!(fold("Crème Brûlée"), "creme brulee");
+    }
+
+    #[test]
+    fn tokenize_splits_on_punctuation() {
+        assert_eq!(
+            tokenize("  Jabón-líquido, 500ml / ÁLOE "),
+            vec!["jabon", "liquido", "500ml", "aloe"]
+        );
+        assert!(tokenize(" -- ").is_empty());
+    }
+
+    #[test]
+    fn search_ignores_accents_and_case() {
+        assert_eq!(index().search("jabon")[0], 0);
+    }
+
+    #[test]
+    fn search_ranks_exact_words_before_prefixes() {
+        assert_eq!(index().search("jabon"), vec![0, 1]);
+        assert_eq!(index().search("deter"), vec![2]);
+        assert_eq!(index().search("pap hig"), vec![3]);
+    }
+
+    #[test]
+    fn search_tolerates_a_typo() {
+        assert_eq!(index().search("detregente"), vec![2]);
+        assert_eq!(index().search("higenico"), vec![3]);
+        // Too short to allow typos
+        assert!(index().search("papl").is_empty());
+    }
+
+    #[test]
+    fn search_needs_every_word() {
+        assert_eq!(index().search("jabon baño"), vec![1]);
+        assert!(index().search("jabon polvo").is_empty());
+    }
+
+    #[test]
+    fn empty_search_returns_everything_in_order() {
+        assert_eq!(index().search(""), vec![0, 1, 2, 3, 4]);
+        assert_eq!(index().search("  "), vec![0, 1, 2, 3, 4]);
+    }
+}
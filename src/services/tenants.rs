@@ -0,0 +1,200 @@
+use crate::config::{DatabaseConfig, TrainingConfig};
+use crate::models::db::DatabaseSettings;
+use crate::models::schema::SchemaMapping;
+use crate::services::modelfile::is_json;
+use crate::services::registry::ModelRegistry;
+use crate::services::schedule::{ScheduleEntry, TrainingSchedule};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+const DEFAULT_TENANT: &str = "default";
+const DEFAULT_MODEL_FILE: &str = "./data/model.bin";
+const DEFAULT_MATRIX_FILE: &str = "./data/matrix.json";
+const DEFAULT_REGISTRY_DIR: &str = "./data/models";
+
+/// A company served by this process, with its own database, model file and training
+/// schedule
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub name: String,
+    pub database: DatabaseSettings,
+    /// Model being served, a copy of the active version in the registry
+    pub model_file: String,
+    /// Directory keeping the last trained models
+    pub registry_dir: String,
+    /// Cache of the extracted interaction matrix, updated incrementally
+    pub matrix_file: String,
+    pub schedule: TrainingSchedule,
+    pub training: TrainingConfig,
+}
+
+impl Tenant {
+    pub fn registry(&self) -> ModelRegistry {
+        ModelRegistry::new(
+            &self.registry_dir,
+            &self.model_file,
+            self.training.registry_keep,
+        )
+    }
+}
+
+pub struct TenantsConfig {
+    /// Tenant answering the routes without a `/t/{tenant}` prefix
+    pub default: String,
+    pub tenants: Vec<Tenant>,
+}
+
+/// A file listing the tenants
+#[derive(Deserialize)]
+pub(crate) struct TenantsFile {
+    pub(crate) default: Option<String>,
+    #[serde(rename = "tenant")]
+    pub(crate) tenants: Vec<TenantEntry>,
+}
+
+/// Per-tenant overrides; anything left out falls back to the `[database]` and
+/// `[training]` settings
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TenantEntry {
+    name: String,
+    /// Aspel SAE company number, used as the suffix of every table name
+    company: Option<Company>,
+    db_type: Option<String>,
+    db_host: Option<String>,
+    db_port: Option<u16>,
+    db_username: Option<String>,
+    db_password: Option<String>,
+    db_name: Option<String>,
+    files_dir: Option<String>,
+    schema_file: Option<String>,
+    model_file: Option<String>,
+    registry_dir: Option<String>,
+    matrix_file: Option<String>,
+    schedule: Option<ScheduleEntry>,
+}
+
+/// A company number, written either as `company = 1` or `company = "01"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Company {
+    Number(u32),
+    Text(String),
+}
+
+impl Company {
+    fn digits(&self) -> String {
+        match self {
+            Company::Number(number) => number.to_string(),
+            Company::Text(text) => text.trim().to_string(),
+        }
+    }
+}
+
+impl TenantEntry {
+    fn into_tenant(
+        self,
+        defaults: &DatabaseConfig,
+        training: &TrainingConfig,
+    ) -> Result<Tenant, Box<dyn std::error::Error>> {
+        let schema_file = self.schema_file.as_ref().or(defaults.schema_file.as_ref());
+        let mut schema = SchemaMapping::load(schema_file.map(String::as_str), &defaults.schema)?;
+        if let Some(company) = &self.company {
+            schema
+                .set_company(&company.digits())
+                .map_err(|e| format!("Invalid tenant '{}': {}", self.name, e))?;
+        }
+        let database = DatabaseSettings {
+            db_type: self
+                .db_type
+                .or_else(|| defaults.db_type.clone())
+                .unwrap_or_default(),
+            host: self.db_host.or_else(|| defaults.host.clone()),
+            port: self.db_port.or(defaults.port),
+            username: self.db_username.or_else(|| defaults.username.clone()),
+            password: self.db_password.or_else(|| defaults.password.clone()),
+            name: self.db_name.or_else(|| defaults.name.clone()),
+            files_dir: self.files_dir.or_else(|| defaults.files_dir.clone()),
+            schema,
+            pool_size: defaults.pool_size,
+        };
+
+        let model_file = self
+            .model_file
+            .unwrap_or_else(|| format!("./data/{}/model.bin", self.name));
+        // Published models must be verifiable, which only the binary format is
+        if is_json(&model_file) {
+            return Err(format!(
+                "Invalid tenant '{}': model_file {} must not be a .json file",
+                self.name, model_file
+            )
+            .into());
+        }
+
+        Ok(Tenant {
+            model_file,
+            registry_dir: self
+                .registry_dir
+                .unwrap_or_else(|| format!("./data/{}/models", self.name)),
+            matrix_file: self
+                .matrix_file
+                .unwrap_or_else(|| format!("./data/{}/matrix.json", self.name)),
+            schedule: match self.schedule {
+                Some(schedule) => schedule
+                    .into_schedule(training.timezone())
+                    .map_err(|e| format!("Invalid schedule for tenant '{}': {}", self.name, e))?,
+                None => TrainingSchedule::single(&training.schedule, training.timezone()),
+            },
+            training: training.clone(),
+            name: self.name,
+            database,
+        })
+    }
+}
+
+/// Builds the tenants listed in `source`. Without any, a single `default` tenant reads
+/// from the `[database]` settings.
+pub(crate) fn build_tenants(
+    default: Option<String>,
+    entries: Vec<TenantEntry>,
+    database: &DatabaseConfig,
+    training: &TrainingConfig,
+    source: &str,
+) -> Result<TenantsConfig, Box<dyn std::error::Error>> {
+    if entries.is_empty() {
+        let tenant = TenantEntry {
+            name: DEFAULT_TENANT.to_string(),
+            model_file: Some(DEFAULT_MODEL_FILE.to_string()),
+            registry_dir: Some(DEFAULT_REGISTRY_DIR.to_string()),
+            matrix_file: Some(DEFAULT_MATRIX_FILE.to_string()),
+            ..TenantEntry::default()
+        }
+        .into_tenant(database, training)?;
+        return Ok(TenantsConfig {
+            default: DEFAULT_TENANT.to_string(),
+            tenants: vec![tenant],
+        });
+    }
+
+    let mut names = HashSet::new();
+    if let Some(entry) = entries
+        .iter()
+        .find(|entry| !names.insert(entry.name.as_str()))
+    {
+        return Err(format!("Tenant '{}' is defined twice in {}", entry.name, source).into());
+    }
+
+    let tenants = entries
+        .into_iter()
+        .map(|entry| entry.into_tenant(database, training))
+        .collect::<Result<Vec<_>, _>>()?;
+    let default = match default {
+        Some(default) => default,
+        None => tenants[0].name.clone(),
+    };
+    if !tenants.iter().any(|tenant| tenant.name == default) {
+        return Err(format!("Default tenant '{}' is not defined in {}", default, source).into());
+    }
+
+    Ok(TenantsConfig { default, tenants })
+}
@@ -1,15 +1,18 @@
 use crate::models::db::ClientProductMatrix;
 use crate::services::als::ALS;
-use futures::FutureExt;
+use crate::services::events::{TrainingEvent, TrainingEvents};
+use crate::services::modelfile::ModelMetadata;
+use crate::services::promotion::PromotionGate;
+use crate::services::registry::ModelRegistry;
+use chrono::{DateTime, Utc};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::collections::HashMap;
-use std::fs::File;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::sync::Notify;
+use tracing::{error, info, info_span, warn, Span};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Hyperparameters {
@@ -28,6 +31,193 @@ pub struct JSONData {
     pub product_index: HashMap<String, usize>,
 }
 
+/// Values tried by the hyperparameter search; every combination is trained
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HyperparameterGrid {
+    pub num_factors: Vec<usize>,
+    pub regularization: Vec<f64>,
+    pub confidence_multiplier: Vec<f64>,
+    pub max_iterations: usize,
+}
+
+impl Default for HyperparameterGrid {
+    fn default() -> Self {
+        HyperparameterGrid {
+            num_factors: vec![20, 50, 100, 200],
+            regularization: vec![0.01, 0.1],
+            confidence_multiplier: vec![20.0, 40.0, 60.0],
+            max_iterations: 200,
+        }
+    }
+}
+
+impl HyperparameterGrid {
+    /// Trains `hyperparameters` only, for `max_iterations`
+    pub fn single(hyperparameters: &Hyperparameters, max_iterations: usize) -> Self {
+        HyperparameterGrid {
+            num_factors: vec![hyperparameters.num_factors],
+            regularization: vec![hyperparameters.regularization],
+            confidence_multiplier: vec![hyperparameters.confidence_multiplier],
+            max_iterations,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.num_factors.is_empty()
+            || self.regularization.is_empty()
+            || self.confidence_multiplier.is_empty()
+        {
+            return Err("Every hyperparameter needs at least one value".to_string());
+        }
+        if self.max_iterations < 1 {
+            return Err("max_iterations must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrainingState {
+    Running,
+    Published,
+    /// Trained but refused by the promotion gate
+    Rejected,
+    Cancelled,
+    Failed,
+}
+
+impl TrainingState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrainingState::Running => "running",
+            TrainingState::Published => "published",
+            TrainingState::Rejected => "rejected",
+            TrainingState::Cancelled => "cancelled",
+            TrainingState::Failed => "failed",
+        }
+    }
+}
+
+/// Progress of a training run, shared between the run and the status API
+pub struct TrainingRun {
+    /// Cancels the run's ALS fits
+    notify: Arc<Notify>,
+    cancelled: AtomicBool,
+    processed: AtomicUsize,
+    total: AtomicUsize,
+    best_epr: Mutex<Option<f64>>,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    outcome: Mutex<Option<(TrainingState, String)>>,
+    events: TrainingEvents,
+}
+
+/// Snapshot of a training run returned by the status API
+#[derive(Debug, Serialize)]
+pub struct TrainingStatus {
+    pub state: TrainingState,
+    pub message: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub processed: usize,
+    pub total: usize,
+    pub percent: f64,
+    pub best_epr: Option<f64>,
+    pub elapsed_secs: f64,
+    /// Estimated from the average time per combination so far
+    pub eta_secs: Option<f64>,
+}
+
+impl TrainingRun {
+    /// A run publishing its progress to `events`
+    pub fn new(events: TrainingEvents) -> Arc<Self> {
+        Arc::new(TrainingRun {
+            notify: Arc::new(Notify::new()),
+            cancelled: AtomicBool::new(false),
+            processed: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            best_epr: Mutex::new(None),
+            started_at: Utc::now(),
+            started: Instant::now(),
+            outcome: Mutex::new(None),
+            events,
+        })
+    }
+
+    /// Stops the combinations still to train and the fits in progress; the run
+    /// publishes nothing
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        // A single permit: each fit that consumes it passes it on to the next one
+        self.notify.notify_one();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.outcome.lock().unwrap().is_none()
+    }
+
+    /// Records the outcome of the run; only the first one counts
+    pub fn finish(&self, state: TrainingState, message: impl Into<String>) {
+        let mut outcome = self.outcome.lock().unwrap();
+        if outcome.is_none() {
+            let message = message.into();
+            self.events.publish(TrainingEvent::Finished {
+                state,
+                message: message.clone(),
+            });
+            *outcome = Some((state, message));
+        }
+    }
+
+    fn record_epr(&self, hyperparameters: &Hyperparameters, epr: f64) {
+        let mut best_epr = self.best_epr.lock().unwrap();
+        if best_epr.is_none_or(|best| epr < best) {
+            *best_epr = Some(epr);
+            self.events.publish(TrainingEvent::BestSoFar {
+                hyperparameters: hyperparameters.clone(),
+                epr,
+            });
+        }
+    }
+
+    pub fn status(&self) -> TrainingStatus {
+        let processed = self.processed.load(Ordering::SeqCst);
+        let total = self.total.load(Ordering::SeqCst);
+        let elapsed_secs = self.started.elapsed().as_secs_f64();
+        let outcome = self.outcome.lock().unwrap().clone();
+        let eta_secs = match outcome {
+            None if processed > 0 => {
+                Some(elapsed_secs / processed as f64 * total.saturating_sub(processed) as f64)
+            }
+            _ => None,
+        };
+        let (state, message) = match outcome {
+            Some((state, message)) => (state, Some(message)),
+            None => (TrainingState::Running, None),
+        };
+        TrainingStatus {
+            state,
+            message,
+            started_at: self.started_at,
+            processed,
+            total,
+            percent: if total > 0 {
+                processed as f64 / total as f64 * 100.0
+            } else {
+                0.0
+            },
+            best_epr: *self.best_epr.lock().unwrap(),
+            elapsed_secs,
+            eta_secs,
+        }
+    }
+}
+
 fn generate_hyperparameter_combinations(
     num_factors: &[usize],
     regularization: &[f64],
@@ -49,59 +239,92 @@ fn generate_hyperparameter_combinations(
         .collect()
 }
 
-pub async fn find_best_als_model(
+/// Trains every combination in `grid`, reporting progress to `run`, and publishes the
+/// best model to `registry` if `gate` accepts it. The interactions the gate holds out
+/// for validation are left out of the fits but kept in the published model's matrix,
+/// so the next run trains on them. With `warm_start`, fits start from
+/// that model's factors instead of random ones. CPU-bound and blocking: call it from
+/// `spawn_blocking`.
+pub fn find_best_als_model(
     matrix: ClientProductMatrix,
-    notify: Arc<Notify>,
+    grid: &HyperparameterGrid,
+    warm_start: Option<Arc<JSONData>>,
+    run: &TrainingRun,
+    registry: &ModelRegistry,
+    gate: &PromotionGate,
 ) -> Option<Hyperparameters> {
-    println!("Finding best ALS model...");
-    let num_factors = vec![20, 50, 100, 200];
-    let regularization = vec![0.01, 0.1];
-    let confidence_multiplier = vec![20.0, 40.0, 60.0];
-    // let num_factors = vec![20];
-    // let regularization = vec![0.01];
-    // let confidence_multiplier = vec![20.0];
-
-    let hyperparameter_combinations =
-        generate_hyperparameter_combinations(&num_factors, &regularization, &confidence_multiplier);
+    info!("Finding best ALS model...");
+    let holdout = gate.holdout(&matrix, registry.model_file());
+    let training_matrix = match &holdout {
+        Some(holdout) => without(&matrix, holdout),
+        None => matrix.clone(),
+    };
+    let hyperparameter_combinations = generate_hyperparameter_combinations(
+        &grid.num_factors,
+        &grid.regularization,
+        &grid.confidence_multiplier,
+    );
 
     let total_combinations = hyperparameter_combinations.len();
+    run.total.store(total_combinations, Ordering::SeqCst);
+    run.events.publish(TrainingEvent::Started {
+        total: total_combinations,
+    });
 
-    println!("Total combinations: {}", total_combinations);
+    info!("Total combinations: {}", total_combinations);
 
-    let processed_counter = Arc::new(AtomicUsize::new(0));
+    let processed_counter = &run.processed;
 
     let start_time = Instant::now();
+    // Rayon's threads don't inherit the span of the run
+    let run_span = Span::current();
 
-    let (
-        best_hyperparameters,
-        best_epr,
-        best_client_factors,
-        best_product_factors,
-        best_client_index,
-        best_product_index,
-    ) = hyperparameter_combinations
+    let best = hyperparameter_combinations
         .par_iter()
         .filter_map(|hyperparameters| {
-            let notify = notify.clone();
-            if notify.notified().now_or_never().is_some() {
-                println!("Cancellation requested, stopping find_best_als_model");
+            let _span = info_span!(
+                parent: &run_span,
+                "combination",
+                num_factors = hyperparameters.num_factors,
+                regularization = hyperparameters.regularization,
+                confidence_multiplier = hyperparameters.confidence_multiplier,
+            )
+            .entered();
+            if run.is_cancelled() {
+                warn!("Cancellation requested, stopping find_best_als_model");
                 return None;
             }
 
-            let matrix_clone = matrix.clone(); // Clone the matrix for each ALS instance
+            run.events.publish(TrainingEvent::CombinationStarted {
+                hyperparameters: hyperparameters.clone(),
+            });
+            let matrix_clone = training_matrix.clone(); // Clone the matrix for each ALS instance
             let mut als = ALS::new(
                 hyperparameters.num_factors,
                 hyperparameters.regularization,
                 hyperparameters.confidence_multiplier,
                 1e-4,
-                200,
+                grid.max_iterations,
                 matrix_clone,
             );
-            als.fit(notify.clone());
+            if let Some(model) = &warm_start {
+                als.warm_start(model.clone());
+            }
+            als.fit(run.notify.clone(), Some(&run.events));
+            if run.is_cancelled() {
+                return None;
+            }
             let epr = als.compute_epr().unwrap();
 
             let processed = processed_counter.fetch_add(1, Ordering::SeqCst) + 1;
-            println!(
+            run.events.publish(TrainingEvent::CombinationFinished {
+                hyperparameters: hyperparameters.clone(),
+                epr,
+                processed,
+                total: total_combinations,
+            });
+            run.record_epr(hyperparameters, epr);
+            info!(
                 "Processed {}/{} combinations EPR: {:.2}% ({:.2}%) | Metadata: num_factors: {}, regularization: {}, confidence_multiplier: {}",
                 processed,
                 total_combinations,
@@ -139,12 +362,35 @@ pub async fn find_best_als_model(
                 product_index,
             ))
         })
-        .min_by(|(_, epr1, _, _, _, _), (_, epr2, _, _, _, _)| epr1.partial_cmp(epr2).unwrap())?;
+        .min_by(|(_, epr1, _, _, _, _), (_, epr2, _, _, _, _)| epr1.partial_cmp(epr2).unwrap());
+    let Some((
+        best_hyperparameters,
+        best_epr,
+        best_client_factors,
+        best_product_factors,
+        best_client_index,
+        best_product_index,
+    )) = best
+    else {
+        if run.is_cancelled() {
+            run.finish(TrainingState::Cancelled, "Training cancelled");
+        } else {
+            run.finish(
+                TrainingState::Failed,
+                "No hyperparameter combination trained",
+            );
+        }
+        return None;
+    };
+    if run.is_cancelled() {
+        run.finish(TrainingState::Cancelled, "Training cancelled");
+        return None;
+    }
 
     let elapsed_time = start_time.elapsed();
-    println!("Best EPR: {:?}%", best_epr * 100.0);
-    println!("Best hyperparameters: {:?}", best_hyperparameters);
-    println!(
+    info!("Best EPR: {:?}%", best_epr * 100.0);
+    info!("Best hyperparameters: {:?}", best_hyperparameters);
+    info!(
         "Time taken to process all combinations: {:.2?}",
         elapsed_time
     );
@@ -162,17 +408,57 @@ pub async fn find_best_als_model(
         product_index: best_product_index,
     };
 
-    save_hyperparameters_to_file(&json_data, "./data/hyperparameters.json")
-        .expect("Failed to save hyperparameters");
+    let metadata = ModelMetadata::new(&json_data, Some(best_epr));
+    publish_model(&json_data, metadata, holdout.as_ref(), registry, gate, run)
+        .then_some(best_hyperparameters)
+}
 
-    Some(best_hyperparameters)
+/// `matrix` with the interactions of `holdout` set to zero, so every client and
+/// product keeps its place in the factors
+fn without(matrix: &ClientProductMatrix, holdout: &ClientProductMatrix) -> ClientProductMatrix {
+    let mut matrix = matrix.clone();
+    for (client_id, products) in holdout {
+        if let Some(client_products) = matrix.get_mut(client_id) {
+            for product_id in products.keys() {
+                if let Some(quantity) = client_products.get_mut(product_id) {
+                    *quantity = 0.0;
+                }
+            }
+        }
+    }
+    matrix
 }
 
-fn save_hyperparameters_to_file(
-    data: &JSONData,
-    file_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let file = File::create(file_path)?;
-    serde_json::to_writer(file, data)?;
-    Ok(())
+/// Publishes the model if `gate` accepts it, finishing `run` either way. `false` when
+/// publishing failed.
+fn publish_model(
+    json_data: &JSONData,
+    metadata: ModelMetadata,
+    holdout: Option<&ClientProductMatrix>,
+    registry: &ModelRegistry,
+    gate: &PromotionGate,
+    run: &TrainingRun,
+) -> bool {
+    if let Err(reason) = gate.check(json_data, &metadata, holdout, registry.model_file()) {
+        warn!("Model not published: {}", reason);
+        run.finish(TrainingState::Rejected, reason);
+        return true;
+    }
+    match registry.publish(json_data, metadata) {
+        Ok(version) => {
+            run.finish(
+                TrainingState::Published,
+                format!("Published model version {}", version.version),
+            );
+            true
+        }
+        Err(e) => {
+            error!("Failed to publish model: {}", e);
+            run.finish(
+                TrainingState::Failed,
+                format!("Failed to publish model: {}", e),
+            );
+            false
+        }
+    }
 }